@@ -0,0 +1,47 @@
+//! Brightness/contrast throughput on a 24MP RGB frame: the `wide` kernel against the
+//! scalar per-byte formula it replaced. Run with `cargo bench --bench fused_pass`.
+
+use cliobulk_core::simd::{brightness_contrast, brightness_contrast_simd, FUSED_CHUNK_PIXELS};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rayon::prelude::*;
+use std::hint::black_box;
+
+const WIDTH: usize = 6000;
+const HEIGHT: usize = 4000;
+const CHANNELS: usize = 3;
+
+fn frame() -> Vec<u8> {
+    (0..WIDTH * HEIGHT * CHANNELS).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+fn fused_pass(c: &mut Criterion) {
+    let mut group = c.benchmark_group("brightness_contrast_24mp");
+    group.throughput(Throughput::Bytes((WIDTH * HEIGHT * CHANNELS) as u64));
+    group.sample_size(20);
+    let source = frame();
+
+    group.bench_function("simd", |bench| {
+        let mut buf = source.clone();
+        bench.iter(|| {
+            buf.par_chunks_mut(CHANNELS * FUSED_CHUNK_PIXELS).for_each(|chunk| {
+                brightness_contrast_simd(chunk, CHANNELS, black_box(20.0), black_box(1.2));
+            });
+        });
+    });
+
+    group.bench_function("scalar", |bench| {
+        let mut buf = source.clone();
+        bench.iter(|| {
+            buf.par_chunks_mut(CHANNELS * FUSED_CHUNK_PIXELS).for_each(|chunk| {
+                for v in chunk.iter_mut() {
+                    *v = brightness_contrast(*v, black_box(20.0), black_box(1.2));
+                }
+            });
+        });
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, fused_pass);
+criterion_main!(benches);
@@ -0,0 +1,4 @@
+//! Library side of the ClioBulk-X core: the pieces the `cliobulk-core` binary shares with
+//! its benchmarks.
+
+pub mod simd;
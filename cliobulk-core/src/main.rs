@@ -3,11 +3,13 @@
 //! ---------------------------------------------------------------------------------------
 //! A high-performance, multi-threaded image processing backbone designed for high-throughput
 //! batch operations. This core leverages Rust's safety and concurrency model to handle
-//! both standard web formats and professional digital RAW files (ARW, CR2, NEF, DNG).
+//! both standard web formats and professional digital RAW files (ARW, CR2, NEF, DNG, PEF,
+//! RAF, RW2, ORF).
 //!
 //! FEATURES:
 //! - Parallelized RAW decoding with optimized sub-sampling.
-//! - SIMD-accelerated pixel manipulation (via Rayon and image crates).
+//! - SIMD-accelerated pixel manipulation (brightness/contrast on `wide` f32x8 lanes,
+//!   parallelized with Rayon).
 //! - Single-pass filter application to minimize memory bandwidth overhead.
 //! - Real-time IPC progress reporting via JSON-formatted stdout.
 //!
@@ -17,14 +19,14 @@
 //! ---------------------------------------------------------------------------------------
 
 use clap::Parser;
+use cliobulk_core::simd::{brightness_contrast_simd, FUSED_CHUNK_PIXELS};
 use image::{DynamicImage, ImageBuffer, Rgb};
 use rayon::prelude::*;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::fs::File;
-use std::io::BufReader;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 /// Command-line argument schema for the core processor.
 ///
@@ -34,23 +36,195 @@ use std::io::BufReader;
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Serialized JSON string of `ProcessOptions`.
-    /// Encapsulates all filters and image adjustments to be applied.
-    #[arg(short, long)]
-    options: String,
+    /// Encapsulates all filters and image adjustments to be applied. Fields given here
+    /// override the same fields from `--config`. `@path` reads the JSON from a file
+    /// instead (as in curl), avoiding shell escaping and command-line length limits.
+    #[arg(short = 'O', long, required_unless_present_any = ["print_schema", "config"])]
+    options: Option<String>,
+
+    /// TOML (`.toml`) or JSON file supplying default `ProcessOptions`.
+    /// Precedence, lowest to highest: built-in field defaults, this file, `--options`.
+    /// Merging is per top-level field; a nested object such as `levels` given in
+    /// `--options` replaces the config's object as a whole.
+    #[arg(long)]
+    config: Option<String>,
 
     /// Comma-separated list of absolute paths OR path to a JSON manifest file.
-    #[arg(short, long)]
-    inputs: String,
+    #[arg(short, long, required_unless_present_any = ["print_schema", "preview"])]
+    inputs: Option<String>,
 
     /// Target destination directory for processed outputs.
+    #[arg(short, long, required_unless_present_any = ["print_schema", "output_zip"])]
+    output: Option<String>,
+
+    /// Writes every output (and any thumbnails or sidecars) into this ZIP archive instead
+    /// of an output directory, under the same names they would have on disk. Entries are
+    /// stored uncompressed since the images already are. Reported `output_path`s are the
+    /// archive path followed by the entry name. Cannot be combined with `--resume` or
+    /// `--cache`, whose skipped files would be missing from the new archive.
+    #[arg(long, conflicts_with_all = ["output", "preview", "resume", "cache"])]
+    output_zip: Option<String>,
+
+    /// Number of failed files at which the process exits with a non-zero status.
+    /// The default of 1 fails on any error; 0 always exits successfully.
+    #[arg(long, default_value_t = 1)]
+    fail_on_error: usize,
+
+    /// Suppresses the per-file "processing", "decoding_raw" and "saved" progress lines.
+    /// Error lines and the final completion message are still emitted.
     #[arg(short, long)]
-    output: String,
+    quiet: bool,
+
+    /// Stops starting new files after the first decode/save failure, ends the progress
+    /// stream with an `aborted` status naming that file and exits non-zero.
+    /// Files already in flight on other threads still finish.
+    #[arg(long)]
+    abort_on_error: bool,
+
+    /// Extra decode attempts per file after a transient I/O error (timeouts, resets,
+    /// interrupted reads), with exponential backoff from 200 ms up to 10 s. Malformed or
+    /// unsupported files are never retried. Each attempt emits a `retrying` status.
+    /// With `--verify-output`, also the number of rewrites of an output that fails verification.
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+
+    /// Reads every written output back, checks its checksum against the encoded bytes and
+    /// decodes it to confirm the expected dimensions, catching truncated or corrupted
+    /// writes the OS did not report. A failing output is rewritten up to `--retries` times
+    /// and then reported as `error: output verification failed`. Off by default since it
+    /// doubles output I/O. Thumbnails and sidecars are not verified.
+    #[arg(long, conflicts_with = "output_zip")]
+    verify_output: bool,
+
+    /// Prints a `BenchSummary` JSON line (throughput and per-stage timings) after the
+    /// final status line. Outputs are still written, so encode cost is included.
+    #[arg(long)]
+    bench: bool,
+
+    /// Comma-separated extensions to keep (e.g. `arw,cr2`); other inputs are dropped
+    /// before the batch starts and are not counted in `total`. Case-insensitive; a
+    /// leading dot is optional.
+    #[arg(long, value_delimiter = ',')]
+    only_ext: Vec<String>,
+
+    /// Comma-separated extensions to drop, applied after `--only-ext`.
+    #[arg(long, value_delimiter = ',')]
+    skip_ext: Vec<String>,
+
+    /// Orders inputs by EXIF `DateTimeOriginal` (files without it follow, by modification
+    /// time) and prefixes each output name with its 1-based chronological position, e.g.
+    /// `processed_0003_IMG_1234.jpg`. Applied before `--limit`.
+    #[arg(long)]
+    sort_by_capture_time: bool,
+
+    /// Reproducible runs for regression testing: inputs are sorted by path before any other
+    /// ordering (`--sort-by-capture-time` then breaks its ties by path), each file's
+    /// progress `index` is its position in that order rather than when a worker picked it
+    /// up, and the `--report` lists entries by input path instead of completion order.
+    /// Outputs, sidecars, contact sheets and reports are then byte-identical across runs
+    /// with the same inputs and options. Files are still processed in parallel, so the
+    /// cost is only the up-front sort, plus the lost ability to prioritize by the order
+    /// given. Not available with `--output-zip`, whose entries are in completion order.
+    #[arg(long, conflicts_with = "output_zip")]
+    deterministic: bool,
+
+    /// Memory-maps inputs instead of reading them into a heap buffer, so the OS pages
+    /// large files in on demand. Helps most for JPEG/PNG/TIFF inputs; `rawloader` still
+    /// copies RAW data into its own buffer. Falls back to a normal read when a file cannot
+    /// be mapped (e.g. on some network filesystems).
+    #[arg(long)]
+    mmap: bool,
+
+    /// Two-pass mode: first measures a shared exposure and white-balance reference over
+    /// a sample of the inputs (emitting `analyzing` statuses), then applies that one
+    /// correction to every file. Replaces per-image `auto_white_balance`. Grayscale
+    /// images receive only the exposure part, never the color cast.
+    #[arg(long)]
+    normalize_batch: bool,
+
+    /// Also writes a proofing montage of every processed output to this path, laid out
+    /// in `--sheet-columns` x `--sheet-rows` cells with filename captions, in input order.
+    /// When more inputs remain than fit on one sheet, further pages are written next to
+    /// it as `<stem>_2.<ext>`, `<stem>_3.<ext>`, and so on. Failed files leave an empty cell.
+    #[arg(long)]
+    contact_sheet: Option<String>,
+
+    /// Cells per row on a contact sheet.
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(1..))]
+    sheet_columns: u32,
+
+    /// Rows per contact sheet page.
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    sheet_rows: u32,
+
+    /// Longest edge in pixels of each contact-sheet thumbnail.
+    #[arg(long, default_value_t = 240, value_parser = clap::value_parser!(u32).range(16..))]
+    sheet_cell: u32,
+
+    /// Culls out-of-focus frames: files whose focus score (see `sharpness`) is below this
+    /// value are not saved and emit a `rejected_blurry` status instead (even with `--quiet`).
+    /// The score is the variance of the Laplacian of the decoded image's luma, measured at
+    /// most 1024 px on the long edge so it is comparable across resolutions. Crisp detailed
+    /// frames typically score in the hundreds or more, motion-blurred or misfocused ones
+    /// below about 50, and featureless frames (sky, blank walls) near 0 whatever their
+    /// focus. Start around 100 and tune against a few known-good frames from the shoot.
+    #[arg(long, value_parser = parse_sharpness)]
+    min_sharpness: Option<f32>,
+
+    /// Processes only the first N resolved inputs; handy for smoke-testing options.
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Writes a `BatchReport` JSON file recording the outcome of every input. It is
+    /// rewritten as each file finishes, so a killed or crashed run still leaves a report
+    /// of everything done so far for `--resume`.
+    #[arg(long)]
+    report: Option<String>,
+
+    /// Loads a previous `BatchReport` and skips inputs it records as successfully saved.
+    /// Inputs that errored or were missing in that run are retried.
+    #[arg(long)]
+    resume: Option<String>,
+
+    /// Appends a plain-text diagnostic log (decode stages, per-file timings, errors with
+    /// their cause chain and, when `RUST_BACKTRACE=1`, backtraces) to this file. The JSON
+    /// progress stream on stdout is unaffected.
+    #[arg(long)]
+    log_file: Option<String>,
+
+    /// Most verbose level written to `--log-file`: `error`, `warn`, `info`, `debug` or `trace`.
+    #[arg(long, default_value_t = tracing::Level::INFO)]
+    log_level: tracing::Level,
+
+    /// JSON cache mapping each input path to a hash of its content, effective options,
+    /// LUT and mask file contents and core version, plus the output it produced. Inputs whose hash matches their cached entry are not
+    /// reprocessed (status `cached`), even if that output has since been deleted. The
+    /// file is created if missing and rewritten after every successful file.
+    #[arg(long)]
+    cache: Option<String>,
+
+    /// Writes a `Sidecar` JSON file next to each output (same name, `.json` extension)
+    /// recording the effective options, core version and source path. A failed sidecar
+    /// write is logged and does not fail the image.
+    #[arg(long)]
+    write_sidecar: bool,
+
+    /// Preview mode: processes only this file and writes `preview_<name>.png` to the
+    /// output directory, with the original on the left and the result on the right,
+    /// then exits. The `saved` status carries the preview path.
+    #[arg(long, conflicts_with = "inputs")]
+    preview: Option<String>,
+
+    /// Prints the JSON schema of `ProcessOptions` and exits.
+    /// Lets the front-end validate its payload against the exact core build it talks to.
+    #[arg(long)]
+    print_schema: bool,
 }
 
 /// Image adjustment parameters and filter toggles.
 ///
 /// Designed to be compatible with JSON serialization for cross-language IPC.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 struct ProcessOptions {
     /// Normalized brightness offset: -1.0 (black) to 1.0 (white).
     pub brightness: f32, 
@@ -62,231 +236,3759 @@ struct ProcessOptions {
     pub adaptive_threshold: bool,
     /// Toggles median-filter based denoising to reduce sensor noise.
     pub denoise: bool,
+    /// Demosaic algorithm used for Bayer RAW files (`fast`, `bilinear` or `vng`).
+    #[serde(default)]
+    pub raw_demosaic: DemosaicQuality,
+    /// Longest edge in pixels of an optional thumbnail written to `thumbs/` alongside
+    /// the full output, reusing the already processed image. `None` disables it.
+    #[serde(default)]
+    pub thumbnail: Option<u32>,
+    /// Interpolation filter used whenever an output is resized (e.g. thumbnails).
+    #[serde(default)]
+    pub resize_filter: ResizeFilter,
+    /// Standard deviation in pixels of an optional Gaussian blur, applied after the
+    /// color adjustments and denoising (0.1 to 100.0). `None` skips the blur entirely.
+    #[serde(default)]
+    pub blur_sigma: Option<f32>,
+    /// Number of output levels per channel for posterization (2 to 32), applied at the
+    /// end of the fused color pass. `None` disables it.
+    #[serde(default)]
+    pub posterize_levels: Option<u8>,
+    /// Produces a negative (`255 - v` per channel) as the last step of the fused color pass.
+    /// Runs before adaptive thresholding, so a thresholded output is inverted too.
+    #[serde(default)]
+    pub invert: bool,
+    /// Hue rotation in degrees: -180.0 to 180.0 (0.0 is neutral). Applied right after
+    /// saturation in the fused color pass; luminance is preserved.
+    #[serde(default)]
+    pub hue_degrees: f32,
+    /// Block radius in pixels for adaptive thresholding (at least 1). Larger values suit
+    /// high-DPI scans with big glyphs. Defaults to 10 when `None`.
+    #[serde(default)]
+    pub adaptive_threshold_radius: Option<u32>,
+    /// Flips the adaptive-threshold result, producing white text on a black background.
+    #[serde(default)]
+    pub adaptive_threshold_invert: bool,
+    /// Uses the adaptive-threshold result as an alpha mask over the color image instead of
+    /// replacing it, producing RGBA output: white areas stay opaque and black areas become
+    /// transparent. Combine with `adaptive_threshold_invert` to keep dark text and drop
+    /// the paper.
+    #[serde(default)]
+    pub threshold_as_mask: bool,
+    /// Keys out a backdrop color (e.g. a green screen) to transparency. Forces the RGBA
+    /// path, so JPEG output falls back to PNG. `None` disables it.
+    #[serde(default)]
+    pub chroma_key: Option<ChromaKey>,
+    /// Gray-world automatic white balance: scales R, G and B so their means match, as the
+    /// first step of the fused color pass. Skipped for grayscale and near-monochrome
+    /// (single-hue) images, where the gray-world assumption does not hold.
+    #[serde(default)]
+    pub auto_white_balance: bool,
+    /// Straightens slightly rotated scans before any other filter runs. The skew is
+    /// estimated from the dominant near-horizontal edges (text lines) and corrected
+    /// only when it lies within ±15°; anything steeper is left alone. Only document-style
+    /// inputs are touched: those with `adaptive_threshold` set, or whose tones cluster
+    /// around ink and paper. Photos (horizons, architecture) are never rotated.
+    #[serde(default)]
+    pub deskew: bool,
+    /// Solid frame added as the last filter step. `None` disables it.
+    #[serde(default)]
+    pub border: Option<Border>,
+    /// Applies Floyd-Steinberg error diffusion when reducing 16-bit RAW or other
+    /// high-precision sources to 8 bits, reducing banding in smooth gradients.
+    /// Off by default: the diffusion is serial per image and limits parallelism.
+    #[serde(default)]
+    pub dither: bool,
+    /// Channel mixer: a 3x3 matrix in row-major order, so output R is
+    /// `m[0] * r + m[1] * g + m[2] * b`, G uses `m[3..6]` and B uses `m[6..9]`. Applied in
+    /// the fused color pass right after hue rotation. The identity matrix is a no-op.
+    #[serde(default)]
+    pub color_matrix: Option<[f32; 9]>,
+    /// Path to a 3D `.cube` LUT applied with trilinear interpolation in the fused color
+    /// pass, after hue rotation and before posterization. The file is parsed once per
+    /// batch. `None` disables it.
+    #[serde(default)]
+    pub lut: Option<PathBuf>,
+    /// Path to a grayscale mask image limiting where `brightness`, `contrast` and
+    /// `saturation` apply: fully where the mask is white, not at all where it is black,
+    /// blended in between. The mask is stretched (bilinear) to each image's size, so one
+    /// mask serves a fixed layout shot at any resolution. Loaded once per batch; other
+    /// adjustments stay global. `None` applies them everywhere.
+    #[serde(default)]
+    pub mask: Option<PathBuf>,
+    /// Photoshop-style levels remap, applied at the start of the fused color pass (after
+    /// white balance).
+    /// `None` disables it.
+    #[serde(default)]
+    pub levels: Option<Levels>,
+    /// Tone curves, applied right after `levels` in the fused color pass. `None`
+    /// disables them.
+    #[serde(default)]
+    pub curves: Option<Curves>,
+    /// Encoder for the processed output (`jpeg`, `png`, `webp` or `auto`).
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    /// Chroma subsampling for JPEG outputs and thumbnails (`cs444`, `cs422` or `cs420`).
+    /// The default `cs444` keeps full color resolution, which colored text and UI edges
+    /// need; `cs420` gives noticeably smaller photos. Ignored for other formats.
+    #[serde(default)]
+    pub jpeg_chroma_subsampling: ChromaSubsampling,
+    /// Size cap in bytes for JPEG outputs. The highest quality that fits is found by
+    /// binary search over in-memory encodes; if even quality 10 is too large, that
+    /// smallest encode is written anyway. Ignored for other formats.
+    #[serde(default)]
+    pub max_output_bytes: Option<u64>,
 }
 
-/// Structured progress update for IPC.
+/// Resampling filter for resize steps, mapped onto `image::imageops::FilterType`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum ResizeFilter {
+    /// No interpolation; keeps hard pixel edges for pixel-art batches.
+    Nearest,
+    /// Linear interpolation; soft but artifact-free.
+    Triangle,
+    /// Cubic interpolation; a balance between sharpness and ringing.
+    CatmullRom,
+    /// Windowed sinc; sharpest, with slight ringing around high-contrast edges.
+    #[default]
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for image::imageops::FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => image::imageops::FilterType::Nearest,
+            ResizeFilter::Triangle => image::imageops::FilterType::Triangle,
+            ResizeFilter::CatmullRom => image::imageops::FilterType::CatmullRom,
+            ResizeFilter::Lanczos3 => image::imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Tonal levels adjustment with input/output black and white points (0 - 255).
 ///
-/// Emitted to stdout as a JSON object, allowing the parent process to 
-/// update UI progress bars and status labels in real-time.
-#[derive(Serialize)]
-struct Progress {
-    /// Completion percentage (0.0 - 100.0).
-    pub progress: f32,
-    /// Filename currently being processed.
-    pub current_file: String,
-    /// State description (e.g., "processing", "error", "complete").
-    pub status: String,
+/// Evaluated per channel value in this order:
+/// 1. Input range: `in_black..=in_white` is stretched to 0.0 - 1.0 (values outside clip).
+/// 2. Midtone gamma: `t = t^(1 / gamma)`; values above 1.0 brighten midtones.
+/// 3. Output range: 0.0 - 1.0 is compressed to `out_black..=out_white`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(default)]
+struct Levels {
+    /// Input value mapped to black. Must be below `in_white`.
+    pub in_black: u8,
+    /// Input value mapped to white.
+    pub in_white: u8,
+    /// Darkest output value.
+    pub out_black: u8,
+    /// Brightest output value.
+    pub out_white: u8,
+    /// Midtone gamma: 0.1 to 10.0 (1.0 is neutral).
+    pub gamma: f32,
 }
 
-/// Decodes professional RAW image files with an emphasis on speed over fidelity.
+impl Default for Levels {
+    fn default() -> Self {
+        Levels { in_black: 0, in_white: 255, out_black: 0, out_white: 255, gamma: 1.0 }
+    }
+}
+
+impl Levels {
+    /// Precomputes the 256-entry lookup table used by the fused pass.
+    fn lut(&self) -> [u8; 256] {
+        let in_black = self.in_black as f32;
+        let in_range = (self.in_white as f32 - in_black).max(1.0);
+        let out_black = self.out_black as f32;
+        let out_range = self.out_white as f32 - out_black;
+        let mut lut = [0u8; 256];
+        for (v, out) in lut.iter_mut().enumerate() {
+            let t = ((v as f32 - in_black) / in_range).clamp(0.0, 1.0).powf(1.0 / self.gamma);
+            *out = (out_black + t * out_range).round().clamp(0.0, 255.0) as u8;
+        }
+        lut
+    }
+}
+
+/// A parsed 3D color lookup table in the Adobe/Resolve `.cube` format.
 ///
-/// Implements a "half-size" demosaicing algorithm that skips full interpolation 
-/// by mapping Bayer patterns directly to RGB pixels. This is ideal for bulk 
-/// processing and preview generation where performance is critical.
-/// 
-/// # Supported Formats
-/// - Sony (.ARW)
-/// - Canon (.CR2)
-/// - Nikon (.NEF)
-/// - Adobe Digital Negative (.DNG)
-/// 
-/// # Arguments
-/// * `path` - Path to the RAW file on disk.
-/// 
-/// # Returns
-/// * `anyhow::Result<DynamicImage>` - The decoded RGB image or a decoding error.
-fn decode_raw(path: &str) -> anyhow::Result<DynamicImage> {
-    let raw = rawloader::decode_file(path).map_err(|e| anyhow::anyhow!(e.to_string()))?;
-    let width = raw.width;
-    let height = raw.height;
-    
-    // Perform parallel demosaicing by sub-sampling the Bayer pattern.
-    // This provides a significant speedup for preview/batch generation.
-    match raw.data {
-        rawloader::RawImageData::Integer(ref data) => {
-            let out_w = width / 2;
-            let out_h = height / 2;
-            let mut vec = vec![0u8; out_w * out_h * 3];
-            
-            vec.par_chunks_exact_mut(out_w * 3)
-                .enumerate()
-                .for_each(|(y, row)| {
-                    for x in 0..out_w {
-                        let idx = (y * 2) * width + (x * 2);
-                        // Sub-sampling R, (G1+G2)/2, B from the Bayer grid
-                        row[x * 3] = (data[idx] >> 8) as u8;
-                        row[x * 3 + 1] = (((data[idx + 1] as u32 + data[idx + width] as u32)) >> 9) as u8;
-                        row[x * 3 + 2] = (data[idx + width + 1] >> 8) as u8;
-                    }
-                });
-            
-            let img = ImageBuffer::<Rgb<u8>, _>::from_raw(out_w as u32, out_h as u32, vec)
-                .ok_or_else(|| anyhow::anyhow!("Failed to create image buffer"))?;
-            Ok(DynamicImage::ImageRgb8(img))
-        },
-        rawloader::RawImageData::Float(ref data) => {
-            let out_w = width / 2;
-            let out_h = height / 2;
-            let mut vec = vec![0u8; out_w * out_h * 3];
-            
-            vec.par_chunks_exact_mut(out_w * 3)
-                .enumerate()
-                .for_each(|(y, row)| {
-                    for x in 0..out_w {
-                        let idx = (y * 2) * width + (x * 2);
-                        row[x * 3] = (data[idx].clamp(0.0, 1.0) * 255.0) as u8;
-                        row[x * 3 + 1] = ((data[idx + 1] + data[idx + width]) * 127.5).clamp(0.0, 255.0) as u8;
-                        row[x * 3 + 2] = (data[idx + width + 1].clamp(0.0, 1.0) * 255.0) as u8;
-                    }
-                });
+/// Entries are stored red-fastest, as in the file, and hold output RGB in 0.0 - 1.0.
+struct CubeLut {
+    /// Grid points per axis (`LUT_3D_SIZE`).
+    size: usize,
+    /// Input value mapped to the first grid point per channel (`DOMAIN_MIN`).
+    domain_min: [f32; 3],
+    /// Input value mapped to the last grid point per channel (`DOMAIN_MAX`).
+    domain_max: [f32; 3],
+    table: Vec<[f32; 3]>,
+}
+
+impl CubeLut {
+    /// Reads and parses a `.cube` file. 1D LUTs are rejected.
+    fn load(path: &Path) -> anyhow::Result<CubeLut> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read LUT {}: {}", path.display(), e))?;
+        CubeLut::parse(&text).map_err(|e| anyhow::anyhow!("invalid LUT {}: {}", path.display(), e))
+    }
+
+    fn parse(text: &str) -> anyhow::Result<CubeLut> {
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut table = Vec::new();
 
-            let img = ImageBuffer::<Rgb<u8>, _>::from_raw(out_w as u32, out_h as u32, vec)
-                .ok_or_else(|| anyhow::anyhow!("Failed to create image buffer"))?;
-            Ok(DynamicImage::ImageRgb8(img))
+        let triple = |parts: &[&str]| -> anyhow::Result<[f32; 3]> {
+            if parts.len() != 3 {
+                anyhow::bail!("expected 3 values, got {}", parts.len());
+            }
+            let mut out = [0.0; 3];
+            for (o, p) in out.iter_mut().zip(parts) {
+                *o = p.parse()?;
+            }
+            Ok(out)
+        };
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts[0] {
+                "TITLE" => {},
+                "LUT_1D_SIZE" => anyhow::bail!("1D LUTs are not supported"),
+                "LUT_3D_SIZE" => size = Some(parts.get(1).ok_or_else(|| anyhow::anyhow!("missing LUT_3D_SIZE value"))?.parse::<usize>()?),
+                "DOMAIN_MIN" => domain_min = triple(&parts[1..])?,
+                "DOMAIN_MAX" => domain_max = triple(&parts[1..])?,
+                // Resolve's single-range form of DOMAIN_MIN / DOMAIN_MAX
+                "LUT_3D_INPUT_RANGE" => {
+                    let [min, max] = parts[1..] else {
+                        anyhow::bail!("expected 2 values for LUT_3D_INPUT_RANGE, got {}", parts.len() - 1);
+                    };
+                    domain_min = [min.parse()?; 3];
+                    domain_max = [max.parse()?; 3];
+                },
+                // Other keywords (LUT_1D_INPUT_RANGE, vendor extensions) don't affect a 3D table
+                keyword if keyword.starts_with(|c: char| c.is_ascii_alphabetic()) => {},
+                _ => table.push(triple(&parts)?),
+            }
+        }
+
+        let size = size.ok_or_else(|| anyhow::anyhow!("missing LUT_3D_SIZE"))?;
+        if size < 2 {
+            anyhow::bail!("LUT_3D_SIZE must be at least 2");
         }
+        if table.len() != size * size * size {
+            anyhow::bail!("expected {} entries for LUT_3D_SIZE {}, found {}", size * size * size, size, table.len());
+        }
+        if (0..3).any(|c| domain_max[c] <= domain_min[c]) {
+            anyhow::bail!("DOMAIN_MAX must exceed DOMAIN_MIN");
+        }
+        Ok(CubeLut { size, domain_min, domain_max, table })
+    }
+
+    /// Maps an 8-bit RGB triple through the LUT using trilinear interpolation.
+    fn apply(&self, rgb: [u8; 3]) -> [u8; 3] {
+        let n = self.size - 1;
+        let mut base = [0usize; 3];
+        let mut frac = [0.0f32; 3];
+        for c in 0..3 {
+            let v = rgb[c] as f32 / 255.0;
+            let t = ((v - self.domain_min[c]) / (self.domain_max[c] - self.domain_min[c])).clamp(0.0, 1.0) * n as f32;
+            base[c] = (t.floor() as usize).min(n - 1);
+            frac[c] = t - base[c] as f32;
+        }
+        let at = |r: usize, g: usize, b: usize| self.table[(b * self.size + g) * self.size + r];
+
+        let mut out = [0u8; 3];
+        for (c, o) in out.iter_mut().enumerate() {
+            let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+            let [r, g, b] = base;
+            let c00 = lerp(at(r, g, b)[c], at(r + 1, g, b)[c], frac[0]);
+            let c10 = lerp(at(r, g + 1, b)[c], at(r + 1, g + 1, b)[c], frac[0]);
+            let c01 = lerp(at(r, g, b + 1)[c], at(r + 1, g, b + 1)[c], frac[0]);
+            let c11 = lerp(at(r, g + 1, b + 1)[c], at(r + 1, g + 1, b + 1)[c], frac[0]);
+            let v = lerp(lerp(c00, c10, frac[1]), lerp(c01, c11, frac[1]), frac[2]);
+            *o = (v * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+        out
     }
 }
 
-/// Applies a chain of visual filters and adjustments to an image.
+/// Backdrop color to remove, with a soft-edged matte.
 ///
-/// To optimize cache locality and reduce memory iterations, primary color 
-/// adjustments (Brightness, Contrast, Saturation) are fused into a single 
-/// parallelized pass over the pixel buffer.
+/// Distance is Euclidean in 8-bit RGB (0 to about 441). Pixels within half the
+/// `tolerance` of `color` become fully transparent. Between half and the full tolerance,
+/// alpha ramps linearly back to the pixel's original opacity, which softens the matte
+/// edge instead of leaving a hard fringe.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+struct ChromaKey {
+    /// Backdrop color as `[r, g, b]`.
+    pub color: [u8; 3],
+    /// Matching radius in RGB distance: 1.0 to 442.0.
+    pub tolerance: f32,
+}
+
+impl ChromaKey {
+    /// Opacity multiplier (0.0 - 1.0) for a pixel of the given color.
+    fn coverage(&self, rgb: &[u8]) -> f32 {
+        let distance = rgb.iter()
+            .zip(self.color)
+            .map(|(&v, k)| (v as f32 - k as f32).powi(2))
+            .sum::<f32>()
+            .sqrt();
+        let inner = self.tolerance / 2.0;
+        ((distance - inner) / (self.tolerance - inner)).clamp(0.0, 1.0)
+    }
+}
+
+/// A solid frame around the processed image.
 ///
-/// # Arguments
-/// * `img` - The source `DynamicImage`.
-/// * `options` - A reference to the `ProcessOptions` to apply.
+/// The border expands the canvas: the output is `2 * width_px` larger in each dimension
+/// and no image pixels are covered. Grayscale (thresholded) outputs get the luma of
+/// `color`; the border is always opaque.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+struct Border {
+    /// Frame thickness in pixels on every side (at least 1).
+    pub width_px: u32,
+    /// Frame color as `[r, g, b]`.
+    pub color: [u8; 3],
+}
+
+/// Tone curves as `[input, output]` control points (0 - 255) per channel.
 ///
-/// # Returns
-/// * `DynamicImage` - The modified image.
-fn apply_filters(img: DynamicImage, options: &ProcessOptions) -> DynamicImage {
-    let mut rgb = img.to_rgb8();
-    
-    // Multi-adjust pass: Processes pixel channels in a single parallel iteration.
-    if options.brightness != 0.0 || options.contrast != 1.0 || options.saturation != 1.0 {
-        let b = options.brightness * 255.0;
-        let c = options.contrast;
-        let s = options.saturation;
-        
-        rgb.pixels_mut().par_bridge().for_each(|pixel| {
-            // Fused Brightness & Contrast
-            for channel in 0..3 {
-                let mut v = pixel[channel] as f32;
-                // Linear adjustment: (v - 128) * c + 128 + b
-                v = (v - 128.0) * c + 128.0 + b;
-                pixel[channel] = v.clamp(0.0, 255.0) as u8;
-            }
-            
-            // Perceptual saturation adjustment using standard ITU-R 601 luma weights
-            if s != 1.0 {
-                let r = pixel[0] as f32;
-                let g = pixel[1] as f32;
-                let b = pixel[2] as f32;
-                let l = 0.299 * r + 0.587 * g + 0.114 * b;
-                pixel[0] = (l + (r - l) * s).clamp(0.0, 255.0) as u8;
-                pixel[1] = (l + (g - l) * s).clamp(0.0, 255.0) as u8;
-                pixel[2] = (l + (b - l) * s).clamp(0.0, 255.0) as u8;
+/// Points are joined by monotone cubic (Fritsch-Carlson) interpolation, which gives
+/// smooth S-curves without overshooting between points. Inputs below the first or above
+/// the last point take that point's output. An empty list leaves the channel unchanged.
+/// `master` applies to all three channels first, then each channel's own curve.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, JsonSchema)]
+#[serde(default)]
+struct Curves {
+    pub master: Vec<[u8; 2]>,
+    pub red: Vec<[u8; 2]>,
+    pub green: Vec<[u8; 2]>,
+    pub blue: Vec<[u8; 2]>,
+}
+
+impl Curves {
+    /// Checks that each non-empty curve has at least two points with increasing inputs.
+    fn validate(&self) -> anyhow::Result<()> {
+        for (name, points) in [("master", &self.master), ("red", &self.red), ("green", &self.green), ("blue", &self.blue)] {
+            if points.len() == 1 {
+                anyhow::bail!("invalid option `curves.{}`: expected at least 2 points", name);
             }
-        });
+            if points.windows(2).any(|w| w[0][0] >= w[1][0]) {
+                anyhow::bail!("invalid option `curves.{}`: point inputs must be strictly increasing", name);
+            }
+        }
+        Ok(())
     }
 
-    let mut final_img = DynamicImage::ImageRgb8(rgb);
+    /// Precomputes the composed master + channel lookup table for R, G and B.
+    fn luts(&self) -> [[u8; 256]; 3] {
+        let master = curve_lut(&self.master);
+        [&self.red, &self.green, &self.blue].map(|points| {
+            let channel = curve_lut(points);
+            std::array::from_fn(|v| channel[master[v] as usize])
+        })
+    }
+}
 
-    // Apply optional Denoising (3x3 Median Filter)
-    if options.denoise {
-        if let DynamicImage::ImageRgb8(rgb_inner) = final_img {
-             final_img = DynamicImage::ImageRgb8(imageproc::filter::median_filter(&rgb_inner, 1, 1));
+/// Interpolates control points into a 256-entry table with monotone cubic Hermite splines.
+fn curve_lut(points: &[[u8; 2]]) -> [u8; 256] {
+    if points.len() < 2 {
+        return std::array::from_fn(|v| v as u8);
+    }
+    let xs: Vec<f32> = points.iter().map(|p| p[0] as f32).collect();
+    let ys: Vec<f32> = points.iter().map(|p| p[1] as f32).collect();
+    let n = points.len();
+    let slopes: Vec<f32> = (0..n - 1).map(|i| (ys[i + 1] - ys[i]) / (xs[i + 1] - xs[i])).collect();
+
+    // Fritsch-Carlson tangents: zero at local extrema, harmonic-style limiting elsewhere
+    let mut tangents = vec![0.0f32; n];
+    tangents[0] = slopes[0];
+    tangents[n - 1] = slopes[n - 2];
+    for i in 1..n - 1 {
+        if slopes[i - 1] * slopes[i] > 0.0 {
+            tangents[i] = (slopes[i - 1] + slopes[i]) / 2.0;
+        }
+    }
+    for i in 0..n - 1 {
+        if slopes[i] == 0.0 {
+            tangents[i] = 0.0;
+            tangents[i + 1] = 0.0;
+            continue;
+        }
+        let a = tangents[i] / slopes[i];
+        let b = tangents[i + 1] / slopes[i];
+        let h = a.hypot(b);
+        if h > 3.0 {
+            tangents[i] = 3.0 / h * a * slopes[i];
+            tangents[i + 1] = 3.0 / h * b * slopes[i];
         }
     }
 
-    // Apply optional Adaptive Thresholding for high-contrast/document-style output
-    if options.adaptive_threshold {
-        let luma = final_img.to_luma8();
-        final_img = DynamicImage::ImageLuma8(imageproc::contrast::adaptive_threshold(&luma, 10));
+    std::array::from_fn(|v| {
+        let x = v as f32;
+        if x <= xs[0] {
+            return ys[0] as u8;
+        }
+        if x >= xs[n - 1] {
+            return ys[n - 1] as u8;
+        }
+        let i = xs.windows(2).position(|w| x < w[1]).unwrap_or(n - 2);
+        let dx = xs[i + 1] - xs[i];
+        let t = (x - xs[i]) / dx;
+        let (t2, t3) = (t * t, t * t * t);
+        let y = (2.0 * t3 - 3.0 * t2 + 1.0) * ys[i]
+            + (t3 - 2.0 * t2 + t) * dx * tangents[i]
+            + (-2.0 * t3 + 3.0 * t2) * ys[i + 1]
+            + (t3 - t2) * dx * tangents[i + 1];
+        y.round().clamp(0.0, 255.0) as u8
+    })
+}
+
+/// Output encoder selection.
+///
+/// Images with an alpha channel are always written as PNG when `Jpeg` is selected
+/// (or chosen by `Auto`), since JPEG cannot store transparency.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum OutputFormat {
+    /// Lossy JPEG with default compression.
+    #[default]
+    Jpeg,
+    /// Lossless PNG.
+    Png,
+    /// Lossless WebP.
+    Webp,
+    /// Picks PNG or JPEG per image from its content (see `OutputFormat::resolve`).
+    Auto,
+}
+
+impl OutputFormat {
+    /// Resolves the concrete encoder for a processed image.
+    ///
+    /// `Auto` chooses PNG for graphics-like content and JPEG for photographs. An image
+    /// counts as graphics when, over every fourth row, either
+    /// - it uses at most `AUTO_PNG_MAX_COLORS` distinct colors (screenshots, line art,
+    ///   thresholded scans), or
+    /// - more than `AUTO_PNG_FLAT_RATIO` of pixels equal their left neighbor (large flat
+    ///   regions that PNG compresses far better than JPEG).
+    fn resolve(self, img: &DynamicImage) -> OutputFormat {
+        match self {
+            OutputFormat::Jpeg | OutputFormat::Auto if img.color().has_alpha() => OutputFormat::Png,
+            OutputFormat::Auto if looks_like_graphics(img) => OutputFormat::Png,
+            OutputFormat::Auto => OutputFormat::Jpeg,
+            explicit => explicit,
+        }
     }
 
-    final_img
+    /// File extension used for outputs.
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg | OutputFormat::Auto => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+        }
+    }
+
+    /// Encoder selection for `DynamicImage::write_to`.
+    fn image_format(self) -> image::ImageFormat {
+        match self {
+            OutputFormat::Jpeg | OutputFormat::Auto => image::ImageFormat::Jpeg,
+            OutputFormat::Png => image::ImageFormat::Png,
+            OutputFormat::Webp => image::ImageFormat::WebP,
+        }
+    }
 }
 
-/// Core Orchestrator for ClioBulk-X.
+/// `OutputFormat::Auto` picks PNG when the sampled image has at most this many colors.
+const AUTO_PNG_MAX_COLORS: usize = 256;
+
+/// `OutputFormat::Auto` picks PNG when more than this share of sampled pixels repeat
+/// their left neighbor exactly.
+const AUTO_PNG_FLAT_RATIO: f32 = 0.6;
+
+/// Chroma subsampling ratio for JPEG encoding.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum ChromaSubsampling {
+    /// Full-resolution chroma; what the `image` JPEG encoder has always produced.
+    #[default]
+    Cs444,
+    /// Chroma halved horizontally.
+    Cs422,
+    /// Chroma halved in both directions; smallest files, softest colored edges.
+    Cs420,
+}
+
+/// Demosaic quality tier for RAW decoding, trading speed for edge fidelity.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum DemosaicQuality {
+    /// Half-size sub-sampling of each 2x2 Bayer cell. Fastest, but halves the resolution
+    /// and shows zippering on high-detail edges.
+    #[default]
+    Fast,
+    /// Full-resolution bilinear interpolation of each missing color from its 3x3 neighbors.
+    Bilinear,
+    /// Full-resolution variable-number-of-gradients interpolation. Slowest, with the
+    /// least false color along edges.
+    Vng,
+}
+
+impl ProcessOptions {
+    /// Checks every field against its documented range.
+    ///
+    /// Returns a descriptive error naming the offending field, so that a malformed
+    /// payload from the GUI surfaces as an actionable message instead of garbage output.
+    fn validate(&self) -> anyhow::Result<()> {
+        check_range("brightness", self.brightness, -1.0, 1.0)?;
+        check_range("contrast", self.contrast, 0.0, 3.0)?;
+        check_range("saturation", self.saturation, 0.0, 2.0)?;
+        check_range("hue_degrees", self.hue_degrees, -180.0, 180.0)?;
+        if let Some(levels) = &self.levels {
+            if levels.in_black >= levels.in_white {
+                anyhow::bail!("invalid option `levels`: in_black ({}) must be below in_white ({})", levels.in_black, levels.in_white);
+            }
+            check_range("levels.gamma", levels.gamma, 0.1, 10.0)?;
+        }
+        if let Some(curves) = &self.curves {
+            curves.validate()?;
+        }
+        if let Some(matrix) = &self.color_matrix {
+            if let Some(v) = matrix.iter().find(|v| !v.is_finite()) {
+                anyhow::bail!("invalid option `color_matrix`: expected finite numbers, got {}", v);
+            }
+        }
+        if let Some(key) = &self.chroma_key {
+            check_range("chroma_key.tolerance", key.tolerance, 1.0, 442.0)?;
+        }
+        if self.adaptive_threshold_radius == Some(0) {
+            anyhow::bail!("invalid option `adaptive_threshold_radius`: expected a radius of at least 1");
+        }
+        if self.max_output_bytes == Some(0) {
+            anyhow::bail!("invalid option `max_output_bytes`: expected a size of at least 1 byte");
+        }
+        if self.border.is_some_and(|b| b.width_px == 0) {
+            anyhow::bail!("invalid option `border.width_px`: expected a width of at least 1 pixel");
+        }
+        if self.thumbnail == Some(0) {
+            anyhow::bail!("invalid option `thumbnail`: expected an edge length of at least 1 pixel");
+        }
+        if let Some(sigma) = self.blur_sigma {
+            check_range("blur_sigma", sigma, 0.1, 100.0)?;
+        }
+        if let Some(levels) = self.posterize_levels {
+            check_range("posterize_levels", levels as f32, 2.0, 32.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ensures a numeric option is finite and within `[min, max]`.
+fn check_range(field: &str, value: f32, min: f32, max: f32) -> anyhow::Result<()> {
+    if !value.is_finite() {
+        anyhow::bail!("invalid option `{}`: expected a finite number, got {}", field, value);
+    }
+    if value < min || value > max {
+        anyhow::bail!("invalid option `{}`: expected {} to {}, got {}", field, min, max, value);
+    }
+    Ok(())
+}
+
+/// A single unit of batch work: one input file and its effective options.
+#[derive(Debug)]
+struct InputJob {
+    /// Path to the source image as given by the caller.
+    path: String,
+    /// Per-file options from the manifest; `None` uses the global `ProcessOptions`.
+    options: Option<ProcessOptions>,
+    /// 1-based chronological position assigned by `--sort-by-capture-time`, used as an
+    /// output name prefix.
+    sequence: Option<usize>,
+}
+
+/// Sort key for `--sort-by-capture-time`. Variant order is the sort order: EXIF-dated
+/// files first, then files dated only by the filesystem, then unreadable ones.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum CaptureTime {
+    /// EXIF `DateTimeOriginal` as `YYYY:MM:DD HH:MM:SS`, which sorts lexicographically.
+    Exif(String),
+    /// Filesystem modification time.
+    Modified(std::time::SystemTime),
+    Unknown,
+}
+
+impl CaptureTime {
+    /// Reads the capture time of a file, falling back from EXIF to mtime.
+    fn of(path: &Path) -> CaptureTime {
+        let exif = std::fs::File::open(path).ok().and_then(|file| {
+            exif::Reader::new().read_from_container(&mut std::io::BufReader::new(file)).ok()
+        });
+        let original = exif.as_ref().and_then(|exif| exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY));
+        if let Some(exif::Value::Ascii(ref values)) = original.map(|field| &field.value) {
+            if let Some(text) = values.first().and_then(|v| std::str::from_utf8(v).ok()) {
+                return CaptureTime::Exif(text.trim().to_string());
+            }
+        }
+        match std::fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(modified) => CaptureTime::Modified(modified),
+            Err(_) => CaptureTime::Unknown,
+        }
+    }
+}
+
+/// Object-form entry of a JSON manifest: `{ "path": "...", "options": { ... } }`.
 ///
-/// Responsible for:
-/// 1. Bootstrapping the CLI environment and parsing parameters.
-/// 2. Discovering input assets (from string lists or JSON manifests).
-/// 3. Spawning a high-concurrency Rayon pool for image processing.
-/// 4. Managing file-system operations and IPC reporting.
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let options: ProcessOptions = serde_json::from_str(&args.options)?;
-    
-    // Resolve input sources: supports raw string lists or JSON path arrays.
-    let input_paths: Vec<String> = if args.inputs.ends_with(".json") && Path::new(&args.inputs).exists() {
-        let file = File::open(&args.inputs)?;
-        let reader = BufReader::new(file);
-        serde_json::from_reader(reader)?
-    } else {
-        args.inputs.split(',').map(|s| s.to_string()).collect()
-    };
+/// `options` is a partial object; any field it sets overrides the global options for
+/// this file only, and omitted fields inherit the global value.
+#[derive(Deserialize)]
+struct ManifestEntry {
+    path: String,
+    #[serde(default)]
+    options: Option<serde_json::Map<String, serde_json::Value>>,
+}
 
-    let total = input_paths.len();
-    let counter = Arc::new(AtomicUsize::new(0));
-    let output_dir = PathBuf::from(&args.output);
+/// Adaptive-threshold block radius used when `adaptive_threshold_radius` is unset.
+const DEFAULT_THRESHOLD_RADIUS: u32 = 10;
 
-    // Ensure output target exists
-    if !output_dir.exists() {
-        std::fs::create_dir_all(&output_dir)?;
+/// Delay before the first `--retries` attempt; doubled for each further attempt.
+const RETRY_BACKOFF_MS: u64 = 200;
+
+/// Longest delay between `--retries` attempts, however many there are.
+const RETRY_MAX_BACKOFF_MS: u64 = 10_000;
+
+/// Backoff before retry number `attempt` (1-based): `RETRY_BACKOFF_MS` doubled per
+/// earlier attempt, capped at `RETRY_MAX_BACKOFF_MS`.
+fn retry_delay(attempt: u32) -> std::time::Duration {
+    // Past the cap the exponent no longer matters; bounding it keeps the shift in range
+    let doublings = attempt.saturating_sub(1).min(16);
+    std::time::Duration::from_millis((RETRY_BACKOFF_MS << doublings).min(RETRY_MAX_BACKOFF_MS))
+}
+
+/// JPEG quality used when `max_output_bytes` is unset (the `image` encoder's default).
+const JPEG_DEFAULT_QUALITY: u8 = 75;
+
+/// Lowest JPEG quality tried when searching for an encode under `max_output_bytes`.
+const JPEG_MIN_QUALITY: u8 = 10;
+
+/// `auto_white_balance` leaves an image alone when its pixels' chroma vectors agree in
+/// direction at least this much (0.0 = scattered hues, 1.0 = a single hue).
+const AWB_MONOCHROME_COHERENCE: f32 = 0.9;
+
+/// Clamp for each gray-world channel gain, so a dominant subject color cannot push the
+/// correction into an extreme cast of its own.
+const AWB_MAX_GAIN: f32 = 2.0;
+
+/// Most inputs decoded by the `--normalize-batch` analysis pass; larger batches are
+/// sampled at even intervals.
+const NORMALIZE_SAMPLE_FILES: usize = 32;
+
+/// Longest edge of the downscaled copy each analysed image is measured on.
+const NORMALIZE_ANALYSIS_EDGE: u32 = 256;
+
+/// Mean luma `--normalize-batch` moves the batch towards (sRGB middle gray).
+const NORMALIZE_TARGET_LUMA: f32 = 118.0;
+
+/// Clamp for the `--normalize-batch` exposure multiplier.
+const NORMALIZE_MAX_EXPOSURE: f32 = 2.0;
+
+/// Largest skew in degrees that `deskew` will correct.
+const DESKEW_MAX_DEGREES: f32 = 15.0;
+
+/// Longest edge of the downscaled copy used for skew detection.
+const DESKEW_ANALYSIS_EDGE: u32 = 1024;
+
+/// Share of pixels that must sit near the ink or paper tone for `deskew` to treat an
+/// image without `adaptive_threshold` as a document.
+const DESKEW_DOCUMENT_SHARE: f32 = 0.8;
+
+/// Distance in luma levels from its tone's mean within which a pixel counts toward
+/// `DESKEW_DOCUMENT_SHARE`.
+const DESKEW_TONE_SPREAD: f32 = 32.0;
+
+/// Smallest luma difference between the ink and paper means of a document.
+const DESKEW_MIN_CONTRAST: f32 = 96.0;
+
+/// Longest edge of the downscaled copy the `--min-sharpness` focus score is measured on.
+const SHARPNESS_ANALYSIS_EDGE: u32 = 1024;
+
+/// Persistent record of a batch run, written with `--report` and read by `--resume`.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct BatchReport {
+    /// One entry per input, in completion order (by input path with `--deterministic`).
+    pub entries: Vec<ReportEntry>,
+}
+
+/// Outcome of a single input within a `BatchReport`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ReportEntry {
+    /// Input path exactly as it was supplied to the core.
+    pub input: String,
+    /// Final state: "saved", "skipped", "cached", "missing", "rejected_blurry" or "error".
+    pub status: String,
+    /// Absolute path of the output, for "saved", "skipped" and "cached" entries; the first
+    /// page's output for multi-page inputs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_path: Option<String>,
+    /// Error description for "error" entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BatchReport {
+    /// Loads a report from disk.
+    fn load(path: &str) -> anyhow::Result<BatchReport> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("cannot read resume report {}: {}", path, e))?;
+        serde_json::from_str(&text).map_err(|e| anyhow::anyhow!("invalid resume report {}: {}", path, e))
     }
 
-    // Parallel Processing Loop: Rayon automatically scales across all available CPU cores.
-    input_paths.into_par_iter().for_each(|path_str| {
-        let path = Path::new(&path_str);
-        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_else(|| "unknown".to_string());
-        
-        let c = counter.fetch_add(1, Ordering::SeqCst);
-        let prog = Progress {
-            progress: (c as f32 / total as f32) * 100.0,
-            current_file: name.clone(),
-            status: "processing".to_string(),
+    /// Adds an entry; with `sorted`, at its place by input path (after equal paths).
+    fn record(&mut self, entry: ReportEntry, sorted: bool) {
+        let at = if sorted {
+            self.entries.partition_point(|e| e.input <= entry.input)
+        } else {
+            self.entries.len()
         };
-        // Print JSON progress update for the parent GUI process
-        println!("{}", serde_json::to_string(&prog).unwrap());
+        self.entries.insert(at, entry);
+    }
 
-        let res = (|| -> anyhow::Result<()> {
-            let name_lower = name.to_lowercase();
-            // Select appropriate decoder based on file extension
-            let mut img = if name_lower.ends_with(".arw") || 
-                           name_lower.ends_with(".cr2") || 
-                           name_lower.ends_with(".nef") || 
-                           name_lower.ends_with(".dng") {
-                decode_raw(&path_str)?
-            } else {
-                image::open(path)?
-            };
+    /// Writes the report through a temporary file so an interrupted run cannot corrupt it.
+    fn save(&self, path: &str) -> anyhow::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
 
-            img = apply_filters(img, &options);
-            // Save as JPEG with default compression
-            let out_path = output_dir.join(format!("processed_{}.jpg", name));
-            img.save(out_path)?;
-            Ok(())
-        })();
+    /// Maps each successfully completed input to its recorded output path.
+    fn completed(&self) -> std::collections::HashMap<&str, &str> {
+        self.entries
+            .iter()
+            .filter(|e| e.status == "saved" || e.status == "skipped" || e.status == "cached")
+            .filter_map(|e| Some((e.input.as_str(), e.output_path.as_deref()?)))
+            .collect()
+    }
+}
 
-        // Error handling during the batch loop: report error but continue with the remaining items.
-        if let Err(e) = res {
-            let err_prog = Progress {
-                progress: (c as f32 / total as f32) * 100.0,
-                current_file: name,
-                status: format!("error: {}", e),
-            };
-            println!("{}", serde_json::to_string(&err_prog).unwrap());
+/// Reproducibility record written next to each output by `--write-sidecar`.
+#[derive(Serialize)]
+struct Sidecar<'a> {
+    /// Version of the core that produced the output.
+    pub core_version: &'static str,
+    /// Input path exactly as it was supplied to the core.
+    pub source: &'a str,
+    /// Absolute path of the output this sidecar describes.
+    pub output: &'a str,
+    /// The effective options, after config, `--options` and manifest merging.
+    pub options: &'a ProcessOptions,
+}
+
+/// Content-hash cache read and written by `--cache`.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct HashCache {
+    /// Keyed by input path exactly as it was supplied to the core; ordered so the file
+    /// is written the same way every time.
+    pub entries: std::collections::BTreeMap<String, CacheEntry>,
+}
+
+/// Last successful result for one input in a `HashCache`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct CacheEntry {
+    /// Hex BLAKE3 hash from `content_hash`: input bytes, effective options, LUT and mask
+    /// contents, and core version.
+    pub hash: String,
+    /// Absolute path of the output produced from that content.
+    pub output_path: String,
+}
+
+impl HashCache {
+    /// Loads the cache, starting empty when the file does not exist yet.
+    fn load(path: &str) -> anyhow::Result<HashCache> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text).map_err(|e| anyhow::anyhow!("invalid cache {}: {}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashCache::default()),
+            Err(e) => Err(anyhow::anyhow!("cannot read cache {}: {}", path, e)),
         }
-    });
+    }
+
+    /// Writes the cache through a temporary file so an interrupted run cannot corrupt it.
+    fn save(&self, path: &str) -> anyhow::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
 
-    // Signal completion to the parent process
-    println!("{}", serde_json::to_string(&Progress {
-        progress: 100.0,
-        current_file: "Done".to_string(),
-        status: "complete".to_string(),
-    }).unwrap());
+/// Hashes an input's bytes together with everything its output depends on: the options,
+/// the contents of the `lut` and `mask` files they name (from `file_hashes`, filled by
+/// `load_shared`) and the core version. Changing any of them invalidates the cache just
+/// like changed content.
+fn content_hash(path: &Path, options: &ProcessOptions, file_hashes: &std::collections::HashMap<PathBuf, blake3::Hash>) -> anyhow::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(std::fs::File::open(path)?)?;
+    hasher.update(serde_json::to_string(options)?.as_bytes());
+    for file in [&options.lut, &options.mask].into_iter().flatten() {
+        let hash = file_hashes.get(file).ok_or_else(|| anyhow::anyhow!("{} was not loaded", file.display()))?;
+        hasher.update(hash.as_bytes());
+    }
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    Ok(hasher.finalize().to_hex().to_string())
+}
 
-    Ok(())
+/// RAW file extensions routed to `decode_raw` instead of the `image` crate decoders.
+const RAW_EXTENSIONS: &[&str] = &["arw", "cr2", "nef", "dng", "pef", "raf", "rw2", "orf"];
+
+/// Whether a lowercased file name has one of the `RAW_EXTENSIONS`.
+fn is_raw_name(name_lower: &str) -> bool {
+    RAW_EXTENSIONS.iter().any(|ext| name_lower.ends_with(&format!(".{}", ext)))
+}
+
+/// Edge length (in photosites) of the averaging cell used for non-Bayer sensors.
+/// Every 3x3 tile of the Fujifilm X-Trans pattern contains at least one R, G and B site.
+const XTRANS_CELL: usize = 3;
+
+/// Header facts about a JPEG stream needed to pick the correct color conversion.
+struct JpegLayout {
+    /// Number of color components declared in the frame header (4 for CMYK/YCCK).
+    components: u8,
+    /// Whether an Adobe APP14 segment is present, which implies inverted CMYK values.
+    adobe: bool,
+}
+
+/// Structured progress update for IPC.
+///
+/// Emitted to stdout as a JSON object, allowing the parent process to 
+/// update UI progress bars and status labels in real-time.
+#[derive(Serialize)]
+struct Progress {
+    /// Completion percentage (0.0 - 100.0); multi-page inputs advance it per page.
+    pub progress: f32,
+    /// Filename currently being processed.
+    pub current_file: String,
+    /// State description (e.g., "processing", "decoding_raw", "error", "complete").
+    pub status: String,
+    /// 1-based position of this file in the batch (0 before any file has started).
+    pub index: usize,
+    /// Number of files in the batch.
+    pub total: usize,
+    /// Absolute path (native separators) of the written output; only set on "saved".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_path: Option<String>,
+}
+
+impl Progress {
+    /// An update without an output path; add one with `with_output`.
+    fn new(progress: f32, current_file: impl Into<String>, status: impl Into<String>, index: usize, total: usize) -> Progress {
+        Progress { progress, current_file: current_file.into(), status: status.into(), index, total, output_path: None }
+    }
+
+    /// Sets `output_path`, for "saved" and for inputs resolved from an earlier run.
+    fn with_output(self, output_path: impl Into<String>) -> Progress {
+        Progress { output_path: Some(output_path.into()), ..self }
+    }
+
+    /// Writes the update to stdout as one JSON line.
+    fn emit(&self) {
+        println!("{}", serde_json::to_string(self).unwrap());
+    }
+}
+
+/// Per-stage timing counters shared by the worker threads for `--bench`.
+///
+/// Durations are summed across threads, so they measure CPU time spent per stage and
+/// can exceed the wall-clock time of a parallel batch.
+#[derive(Default)]
+struct BenchStats {
+    /// Files that were decoded, filtered and saved successfully.
+    files: AtomicUsize,
+    /// Decoded source pixels across those files.
+    pixels: AtomicU64,
+    decode_ns: AtomicU64,
+    filter_ns: AtomicU64,
+    save_ns: AtomicU64,
+}
+
+impl BenchStats {
+    /// Adds the time elapsed since `since` to `counter` and returns it.
+    fn add(counter: &AtomicU64, since: std::time::Instant) -> std::time::Duration {
+        let elapsed = since.elapsed();
+        counter.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        elapsed
+    }
+
+    /// Snapshots the counters into a summary for a batch that took `wall` to run.
+    fn summary(&self, wall: std::time::Duration) -> BenchSummary {
+        let files = self.files.load(Ordering::Relaxed);
+        let ms = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as f64 / 1e6;
+        let (decode_ms, filter_ms, save_ms) = (ms(&self.decode_ns), ms(&self.filter_ns), ms(&self.save_ns));
+        let megapixels = self.pixels.load(Ordering::Relaxed) as f64 / 1e6;
+        let wall_s = wall.as_secs_f64();
+        BenchSummary {
+            status: "bench".to_string(),
+            files,
+            megapixels,
+            wall_ms: wall_s * 1e3,
+            megapixels_per_second: if wall_s > 0.0 { megapixels / wall_s } else { 0.0 },
+            avg_file_ms: if files > 0 { (decode_ms + filter_ms + save_ms) / files as f64 } else { 0.0 },
+            decode_ms,
+            filter_ms,
+            save_ms,
+        }
+    }
+}
+
+/// Throughput report printed by `--bench` as a single JSON line.
+#[derive(Serialize)]
+struct BenchSummary {
+    /// Always "bench", so the parent process can tell it apart from `Progress` lines.
+    pub status: String,
+    /// Files processed successfully.
+    pub files: usize,
+    /// Source megapixels decoded across those files.
+    pub megapixels: f64,
+    /// Wall-clock time of the parallel processing loop.
+    pub wall_ms: f64,
+    /// Source megapixels per wall-clock second.
+    pub megapixels_per_second: f64,
+    /// Mean decode + filter + save time per file (thread time, not wall time).
+    pub avg_file_ms: f64,
+    /// Total time spent decoding, summed across threads.
+    pub decode_ms: f64,
+    /// Total time spent in `apply_filters`, summed across threads.
+    pub filter_ms: f64,
+    /// Total time spent encoding and writing outputs (including thumbnails).
+    pub save_ms: f64,
+}
+
+/// Shared correction computed by the `--normalize-batch` analysis pass.
+#[derive(Debug, Clone, Copy)]
+struct BatchReference {
+    /// Per-channel RGB multipliers combining the batch's gray-world white balance and
+    /// its exposure offset from `NORMALIZE_TARGET_LUMA`.
+    multipliers: [f32; 3],
+    /// The exposure part alone, applied to grayscale images so the batch's color cast
+    /// does not tint them.
+    exposure: f32,
+}
+
+impl BatchReference {
+    /// Derives the reference from per-image mean RGB values, weighting images equally.
+    fn from_means(means: &[[f64; 3]]) -> Option<BatchReference> {
+        if means.is_empty() {
+            return None;
+        }
+        let n = means.len() as f64;
+        let mean: [f64; 3] = std::array::from_fn(|c| means.iter().map(|m| m[c]).sum::<f64>() / n);
+        if mean.iter().any(|&m| m < 1.0) {
+            return None;
+        }
+        let gray = (mean[0] + mean[1] + mean[2]) / 3.0;
+        let luma = 0.299 * mean[0] + 0.587 * mean[1] + 0.114 * mean[2];
+        let exposure = (NORMALIZE_TARGET_LUMA / luma as f32).clamp(1.0 / NORMALIZE_MAX_EXPOSURE, NORMALIZE_MAX_EXPOSURE);
+        let multipliers = mean.map(|m| ((gray / m) as f32).clamp(1.0 / AWB_MAX_GAIN, AWB_MAX_GAIN) * exposure);
+        Some(BatchReference { multipliers, exposure })
+    }
+}
+
+/// Focus score for `--min-sharpness`: variance of the 3x3 Laplacian over the luma of a
+/// copy downscaled to `SHARPNESS_ANALYSIS_EDGE`.
+///
+/// In-focus edges give strong, varied second derivatives; blur flattens them, so the
+/// variance drops. The value depends on scene content as well as focus, which is why the
+/// threshold is best tuned per shoot rather than treated as absolute.
+fn sharpness(img: &DynamicImage) -> f32 {
+    let luma = if img.width().max(img.height()) > SHARPNESS_ANALYSIS_EDGE {
+        img.thumbnail(SHARPNESS_ANALYSIS_EDGE, SHARPNESS_ANALYSIS_EDGE).to_luma8()
+    } else {
+        img.to_luma8()
+    };
+    let laplacian = imageproc::filter::laplacian_filter(&luma);
+    let count = laplacian.len() as f64;
+    if count == 0.0 {
+        return 0.0;
+    }
+    let (sum, sum_sq) = laplacian.iter().fold((0.0f64, 0.0f64), |(sum, sum_sq), &v| {
+        let v = v as f64;
+        (sum + v, sum_sq + v * v)
+    });
+    let mean = sum / count;
+    (sum_sq / count - mean * mean) as f32
+}
+
+/// Parses `--min-sharpness`, which must be a finite, non-negative score.
+fn parse_sharpness(s: &str) -> Result<f32, String> {
+    let value: f32 = s.parse().map_err(|e| format!("{}", e))?;
+    if value.is_finite() && value >= 0.0 {
+        Ok(value)
+    } else {
+        Err(format!("expected a non-negative focus score, got {}", s))
+    }
+}
+
+/// Mean R, G and B of a downscaled copy of `img`, for the `--normalize-batch` pass.
+fn channel_means(img: &DynamicImage) -> [f64; 3] {
+    let small = img.thumbnail(NORMALIZE_ANALYSIS_EDGE, NORMALIZE_ANALYSIS_EDGE).to_rgb8();
+    let mut sums = [0f64; 3];
+    for pixel in small.pixels() {
+        for (sum, v) in sums.iter_mut().zip(pixel.0) {
+            *sum += v as f64;
+        }
+    }
+    let count = (small.width() as f64 * small.height() as f64).max(1.0);
+    sums.map(|s| s / count)
+}
+
+/// Decodes professional RAW image files with an emphasis on speed over fidelity.
+///
+/// By default implements a "half-size" demosaicing algorithm that skips full interpolation 
+/// by mapping Bayer patterns directly to RGB pixels. This is ideal for bulk 
+/// processing and preview generation where performance is critical. The `Bilinear` and
+/// `Vng` quality tiers interpolate at full resolution instead (see `demosaic_full`).
+/// 
+/// # Supported Formats
+/// - Sony (.ARW)
+/// - Canon (.CR2)
+/// - Nikon (.NEF)
+/// - Adobe Digital Negative (.DNG)
+/// - Pentax (.PEF)
+/// - Fujifilm (.RAF)
+/// - Panasonic (.RW2)
+/// - Olympus (.ORF)
+///
+/// # Limitations
+/// Fujifilm X-Trans sensors use a 6x6 color filter array rather than a 2x2 Bayer grid,
+/// so the sub-sampling shortcut would pick the wrong photosites. Any non-Bayer CFA is
+/// instead decoded at one-third size by averaging each color within 3x3 cells
+/// (see `demosaic_cell_average`). Colors are correct, but fine detail is softer.
+/// Bayer sensors are sampled according to their actual layout (RGGB, BGGR, GRBG or
+/// GBRG), read from the CFA by `bayer_offsets`.
+///
+/// Canon's newer CR3 container is not supported by `rawloader`; such files are
+/// rejected before reaching this function with a "CR3 not supported" error.
+/// 
+/// # Arguments
+/// * `path` - Path to the RAW file on disk.
+/// * `options` - The effective `ProcessOptions` for this file; RAW-specific settings
+///   such as `raw_demosaic` are read from here.
+/// * `mmap` - Read the file through a memory map (`--mmap`).
+/// 
+/// # Returns
+/// * `anyhow::Result<DynamicImage>` - The decoded RGB image or a decoding error.
+fn decode_raw(path: &str, options: &ProcessOptions, mmap: bool) -> anyhow::Result<DynamicImage> {
+    // Read the bytes ourselves: `rawloader::decode_file` flattens I/O errors into strings,
+    // which would hide them from `is_transient` and so from `--retries`
+    let bytes = InputBytes::open(Path::new(path), mmap)?;
+    let raw = rawloader::decode(&mut &bytes[..]).map_err(|e| anyhow::anyhow!(e.to_string()))?;
+    match raw.data {
+        rawloader::RawImageData::Integer(ref data) => {
+            demosaic(data, raw.width, raw.height, &raw.cfa, options, |i| data[i] as f32 / 256.0, bayer_cell_int)
+        },
+        rawloader::RawImageData::Float(ref data) => {
+            demosaic(data, raw.width, raw.height, &raw.cfa, options, |i| data[i] * 255.0, bayer_cell_float)
+        },
+    }
+}
+
+/// Picks the demosaic path for decoded sensor data from the CFA and `options`.
+///
+/// `sample` reads a photosite scaled to 0.0 - 255.0 for the full-precision paths; `cell`
+/// reads a whole 2x2 Bayer cell straight to 8-bit for the undithered `Fast` shortcut.
+fn demosaic<T, F>(
+    data: &[T],
+    width: usize,
+    height: usize,
+    cfa: &rawloader::CFA,
+    options: &ProcessOptions,
+    sample: F,
+    cell: fn(&[T], usize, [usize; 4]) -> [u8; 3],
+) -> anyhow::Result<DynamicImage>
+where
+    T: Sync,
+    F: Fn(usize) -> f32 + Sync,
+{
+    let quality = options.raw_demosaic;
+    let dither = options.dither;
+
+    // Non-Bayer sensors (X-Trans) fall back to per-cell color averaging.
+    let Some(offsets) = bayer_offsets(cfa, width) else {
+        return demosaic_cell_average(width, height, cfa, dither, sample);
+    };
+
+    if quality != DemosaicQuality::Fast {
+        return demosaic_full(width, height, cfa, quality, dither, sample);
+    }
+
+    // Dithered output needs the full-precision samples, so it bypasses the 8-bit shortcut below.
+    if dither {
+        return demosaic_half_dithered(width, height, offsets, sample);
+    }
+
+    // Perform parallel demosaicing by sub-sampling the Bayer pattern.
+    // This provides a significant speedup for preview/batch generation.
+    demosaic_half(data, width, height, offsets, cell)
+}
+
+/// Half-size fast path: one RGB pixel per 2x2 Bayer cell, each read by `cell` from the
+/// cell's top-left index and the `bayer_offsets` of its sites.
+fn demosaic_half<T: Sync>(
+    data: &[T],
+    width: usize,
+    height: usize,
+    offsets: [usize; 4],
+    cell: fn(&[T], usize, [usize; 4]) -> [u8; 3],
+) -> anyhow::Result<DynamicImage> {
+    let out_w = width / 2;
+    let out_h = height / 2;
+    let mut vec = vec![0u8; out_w * out_h * 3];
+
+    vec.par_chunks_exact_mut(out_w * 3)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, pixel) in row.chunks_exact_mut(3).enumerate() {
+                pixel.copy_from_slice(&cell(data, (y * 2) * width + (x * 2), offsets));
+            }
+        });
+
+    let img = ImageBuffer::<Rgb<u8>, _>::from_raw(out_w as u32, out_h as u32, vec)
+        .ok_or_else(|| anyhow::anyhow!("Failed to create image buffer"))?;
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+/// Sub-samples R, (G1+G2)/2, B from a cell of 16-bit integer samples.
+fn bayer_cell_int(data: &[u16], idx: usize, [r, g1, g2, b]: [usize; 4]) -> [u8; 3] {
+    [
+        (data[idx + r] >> 8) as u8,
+        ((data[idx + g1] as u32 + data[idx + g2] as u32) >> 9) as u8,
+        (data[idx + b] >> 8) as u8,
+    ]
+}
+
+/// Float counterpart of `bayer_cell_int` for samples normalized to `0.0..=1.0`.
+fn bayer_cell_float(data: &[f32], idx: usize, [r, g1, g2, b]: [usize; 4]) -> [u8; 3] {
+    // Clamp each green, average, then scale, matching the integer branch's `>> 9`
+    let green = (data[idx + g1].clamp(0.0, 1.0) + data[idx + g2].clamp(0.0, 1.0)) / 2.0;
+    [
+        (data[idx + r].clamp(0.0, 1.0) * 255.0) as u8,
+        (green * 255.0) as u8,
+        (data[idx + b].clamp(0.0, 1.0) * 255.0) as u8,
+    ]
+}
+
+/// Locates the red, two green and blue photosites within a 2x2 Bayer cell.
+///
+/// Returns flat-index offsets `[r, g1, g2, b]` from a cell's top-left photosite (a row
+/// below adds `width`), read from the sensor's actual layout so RGGB, BGGR, GRBG and GBRG
+/// cameras all sample the right colors. `None` for CFAs that are not a 2x2 pattern of
+/// one red, two diagonal greens and one blue (X-Trans, CYGM, RGBE).
+fn bayer_offsets(cfa: &rawloader::CFA, width: usize) -> Option<[usize; 4]> {
+    if cfa.width != 2 || cfa.height != 2 {
+        return None;
+    }
+    let (mut red, mut greens, mut blue) = (None, Vec::new(), None);
+    for dy in 0..2 {
+        for dx in 0..2 {
+            let offset = dy * width + dx;
+            match cfa.color_at(dy, dx) {
+                0 => red = Some(offset),
+                1 => greens.push(offset),
+                2 => blue = Some(offset),
+                _ => return None,
+            }
+        }
+    }
+    match (red, greens.as_slice(), blue) {
+        // The greens of a Bayer cell sit on a diagonal: different rows and columns
+        (Some(r), &[g1, g2], Some(b)) if g1 / width != g2 / width && g1 % width != g2 % width => Some([r, g1, g2, b]),
+        _ => None,
+    }
+}
+
+/// Directions probed by the VNG gradient search: N, S, W, E and the four diagonals.
+const VNG_DIRECTIONS: [(isize, isize); 8] = [(-1, 0), (1, 0), (0, -1), (0, 1), (-1, -1), (-1, 1), (1, -1), (1, 1)];
+
+/// Full-resolution demosaic of a Bayer mosaic.
+///
+/// `Bilinear` fills each missing color with the mean of the same-colored photosites in
+/// the surrounding 3x3 window. `Vng` starts from that estimate and refines it with
+/// `vng_refine`. Both produce an image with the full sensor dimensions.
+///
+/// # Arguments
+/// * `width` / `height` - Dimensions of the sensor data in photosites.
+/// * `cfa` - The color filter array describing which color each photosite records.
+/// * `quality` - `Bilinear` or `Vng`; `Fast` is handled by the sub-sampling path.
+/// * `dither` - Whether the final 8-bit quantization uses error diffusion.
+/// * `sample` - Returns the photosite value at a flat index, scaled to 0.0 - 255.0.
+fn demosaic_full<F>(width: usize, height: usize, cfa: &rawloader::CFA, quality: DemosaicQuality, dither: bool, sample: F) -> anyhow::Result<DynamicImage>
+where
+    F: Fn(usize) -> f32 + Sync,
+{
+    let mut vec = vec![0.0f32; width * height * 3];
+
+    vec.par_chunks_exact_mut(width * 3)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, px) in row.chunks_exact_mut(3).enumerate() {
+                let own = cfa.color_at(y, x);
+                let mut sum = [0.0f32; 3];
+                let mut count = [0u32; 3];
+                for sy in y.saturating_sub(1)..(y + 2).min(height) {
+                    for sx in x.saturating_sub(1)..(x + 2).min(width) {
+                        let color = cfa.color_at(sy, sx);
+                        if color < 3 {
+                            sum[color] += sample(sy * width + sx);
+                            count[color] += 1;
+                        }
+                    }
+                }
+                for (channel, out) in px.iter_mut().enumerate() {
+                    *out = if channel == own {
+                        sample(y * width + x)
+                    } else if count[channel] > 0 {
+                        sum[channel] / count[channel] as f32
+                    } else {
+                        0.0
+                    };
+                }
+            }
+        });
+
+    if quality == DemosaicQuality::Vng {
+        vec = vng_refine(&vec, width, height, cfa, &sample);
+    }
+
+    rgb8_from_f32(vec, width, height, dither)
+}
+
+/// Variable-number-of-gradients refinement of a bilinear demosaic.
+///
+/// For each photosite, a gradient is measured in eight directions over a 5x5 window.
+/// Only the directions whose gradient falls below `1.5 * min + 0.5 * (max - min)` are
+/// kept, so interpolation never averages across an edge. The missing colors are then
+/// rebuilt as the recorded value plus the mean color difference along the kept
+/// directions. The two-pixel image border keeps its bilinear values.
+fn vng_refine<F>(bilinear: &[f32], width: usize, height: usize, cfa: &rawloader::CFA, sample: &F) -> Vec<f32>
+where
+    F: Fn(usize) -> f32 + Sync,
+{
+    let mut out = bilinear.to_vec();
+    if width < 5 || height < 5 {
+        return out;
+    }
+
+    let at = |y: usize, x: usize, dy: isize, dx: isize| -> &[f32] {
+        let idx = ((y as isize + dy) as usize * width + (x as isize + dx) as usize) * 3;
+        &bilinear[idx..idx + 3]
+    };
+
+    out.par_chunks_exact_mut(width * 3)
+        .enumerate()
+        .skip(2)
+        .take(height - 4)
+        .for_each(|(y, row)| {
+            for x in 2..width - 2 {
+                let own = cfa.color_at(y, x);
+                if own > 2 {
+                    continue;
+                }
+
+                let mut gradients = [0.0f32; 8];
+                for (g, &(dy, dx)) in gradients.iter_mut().zip(VNG_DIRECTIONS.iter()) {
+                    let center = at(y, x, 0, 0);
+                    let near = at(y, x, dy, dx);
+                    let far = at(y, x, dy * 2, dx * 2);
+                    *g = (0..3)
+                        .map(|c| (near[c] - center[c]).abs() + (far[c] - near[c]).abs())
+                        .sum();
+                }
+                let min = gradients.iter().cloned().fold(f32::MAX, f32::min);
+                let max = gradients.iter().cloned().fold(f32::MIN, f32::max);
+                let threshold = 1.5 * min + 0.5 * (max - min);
+
+                let mut diff = [0.0f32; 3];
+                let mut n = 0.0f32;
+                for (&g, &(dy, dx)) in gradients.iter().zip(VNG_DIRECTIONS.iter()) {
+                    if g <= threshold {
+                        let neighbor = at(y, x, dy, dx);
+                        for (d, &v) in diff.iter_mut().zip(neighbor.iter()) {
+                            *d += v - neighbor[own];
+                        }
+                        n += 1.0;
+                    }
+                }
+
+                let base = sample(y * width + x);
+                for (channel, d) in diff.iter().enumerate() {
+                    row[x * 3 + channel] = if channel == own { base } else { base + d / n };
+                }
+            }
+        });
+
+    out
+}
+
+/// Reduces an arbitrary CFA mosaic to RGB by averaging each color channel within
+/// `XTRANS_CELL`-sized cells.
+///
+/// Slower than the Bayer sub-sampling path but independent of the sensor layout, which
+/// makes it the safe choice for X-Trans and other non-2x2 patterns.
+///
+/// # Arguments
+/// * `width` / `height` - Dimensions of the sensor data in photosites.
+/// * `cfa` - The color filter array describing which color each photosite records.
+/// * `dither` - Whether the final 8-bit quantization uses error diffusion.
+/// * `sample` - Returns the photosite value at a flat index, scaled to 0.0 - 255.0.
+fn demosaic_cell_average<F>(width: usize, height: usize, cfa: &rawloader::CFA, dither: bool, sample: F) -> anyhow::Result<DynamicImage>
+where
+    F: Fn(usize) -> f32 + Sync,
+{
+    let out_w = width / XTRANS_CELL;
+    let out_h = height / XTRANS_CELL;
+    let mut vec = vec![0.0f32; out_w * out_h * 3];
+
+    vec.par_chunks_exact_mut(out_w * 3)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, px) in row.chunks_exact_mut(3).enumerate() {
+                let mut sum = [0.0f32; 3];
+                let mut count = [0u32; 3];
+                for dy in 0..XTRANS_CELL {
+                    for dx in 0..XTRANS_CELL {
+                        let sy = y * XTRANS_CELL + dy;
+                        let sx = x * XTRANS_CELL + dx;
+                        // Color 3 (e.g. emerald) has no RGB slot and is skipped.
+                        let color = cfa.color_at(sy, sx);
+                        if color < 3 {
+                            sum[color] += sample(sy * width + sx);
+                            count[color] += 1;
+                        }
+                    }
+                }
+                for ((out, s), n) in px.iter_mut().zip(sum).zip(count) {
+                    if n > 0 {
+                        *out = s / n as f32;
+                    }
+                }
+            }
+        });
+
+    rgb8_from_f32(vec, out_w, out_h, dither)
+}
+
+/// Half-size Bayer sub-sampling at full precision, for dithered output.
+///
+/// Mirrors the 8-bit shortcut in `decode_raw` (R, mean of both greens, B per 2x2 cell,
+/// located via `offsets` from `bayer_offsets`) but keeps fractional values so
+/// `floyd_steinberg` can diffuse the rounding error.
+fn demosaic_half_dithered<F>(width: usize, height: usize, offsets: [usize; 4], sample: F) -> anyhow::Result<DynamicImage>
+where
+    F: Fn(usize) -> f32 + Sync,
+{
+    let out_w = width / 2;
+    let out_h = height / 2;
+    let mut vec = vec![0.0f32; out_w * out_h * 3];
+
+    vec.par_chunks_exact_mut(out_w * 3)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, px) in row.chunks_exact_mut(3).enumerate() {
+                let idx = (y * 2) * width + (x * 2);
+                let [r, g1, g2, b] = offsets;
+                px[0] = sample(idx + r);
+                px[1] = (sample(idx + g1) + sample(idx + g2)) / 2.0;
+                px[2] = sample(idx + b);
+            }
+        });
+
+    rgb8_from_f32(vec, out_w, out_h, true)
+}
+
+/// Quantizes 0.0 - 255.0 RGB samples into an 8-bit image.
+///
+/// Without dithering values are clamped and truncated in parallel, matching the 8-bit
+/// fast paths. With dithering the error is diffused via `floyd_steinberg`.
+fn rgb8_from_f32(values: Vec<f32>, width: usize, height: usize, dither: bool) -> anyhow::Result<DynamicImage> {
+    let vec = if dither {
+        floyd_steinberg(values, width, 3)
+    } else {
+        values.par_iter().map(|v| v.clamp(0.0, 255.0) as u8).collect()
+    };
+    let img = ImageBuffer::<Rgb<u8>, _>::from_raw(width as u32, height as u32, vec)
+        .ok_or_else(|| anyhow::anyhow!("Failed to create image buffer"))?;
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+/// Floyd-Steinberg error diffusion from 0.0 - 255.0 samples to 8 bits.
+///
+/// Each pixel's rounding error is pushed to its unprocessed neighbors (7/16 right,
+/// 3/16 below-left, 5/16 below, 1/16 below-right), trading banding for fine grain.
+/// The scan is inherently serial. Only the first three (color) channels diffuse error;
+/// a fourth (alpha) channel is simply rounded.
+fn floyd_steinberg(mut values: Vec<f32>, width: usize, channels: usize) -> Vec<u8> {
+    let stride = width * channels;
+    if stride == 0 {
+        return Vec::new();
+    }
+    let rows = values.len() / stride;
+    let mut out = vec![0u8; values.len()];
+    for y in 0..rows {
+        for x in 0..width {
+            for c in 0..channels {
+                let i = y * stride + x * channels + c;
+                let old = values[i].clamp(0.0, 255.0);
+                let new = old.round();
+                out[i] = new as u8;
+                if c >= 3 {
+                    continue;
+                }
+                let err = old - new;
+                if x + 1 < width {
+                    values[i + channels] += err * 7.0 / 16.0;
+                }
+                if y + 1 < rows {
+                    if x > 0 {
+                        values[i + stride - channels] += err * 3.0 / 16.0;
+                    }
+                    values[i + stride] += err * 5.0 / 16.0;
+                    if x + 1 < width {
+                        values[i + stride + channels] += err / 16.0;
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Walks the JPEG marker segments up to the start of scan and reports the frame layout.
+///
+/// Only header segments are inspected, so this is cheap even for very large files.
+/// Returns `None` if the data is not a well-formed JPEG header.
+fn sniff_jpeg(bytes: &[u8]) -> Option<JpegLayout> {
+    if bytes.get(0..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+    let mut pos = 2;
+    let mut components = None;
+    let mut adobe = false;
+    loop {
+        if *bytes.get(pos)? != 0xFF {
+            return None;
+        }
+        // Skip optional fill bytes between markers
+        while *bytes.get(pos + 1)? == 0xFF {
+            pos += 1;
+        }
+        let marker = *bytes.get(pos + 1)?;
+        // Start of scan or end of image: header parsing is complete
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        let len = u16::from_be_bytes([*bytes.get(pos + 2)?, *bytes.get(pos + 3)?]) as usize;
+        let payload = bytes.get(pos + 4..pos + 2 + len)?;
+        match marker {
+            // SOF0..SOF15, excluding DHT (C4), JPG (C8) and DAC (CC)
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                components = Some(*payload.get(5)?);
+            },
+            0xEE if payload.starts_with(b"Adobe") => adobe = true,
+            _ => {},
+        }
+        pos += 2 + len;
+    }
+    Some(JpegLayout { components: components?, adobe })
+}
+
+/// Whole-file contents of an input, either memory-mapped or read onto the heap.
+enum InputBytes {
+    Mapped(memmap2::Mmap),
+    Read(Vec<u8>),
+}
+
+impl InputBytes {
+    /// Loads `path`, mapping it when `mmap` is set and falling back to a plain read
+    /// when the mapping fails.
+    fn open(path: &Path, mmap: bool) -> std::io::Result<InputBytes> {
+        if mmap {
+            let file = std::fs::File::open(path)?;
+            // SAFETY: inputs are treated as read-only for the duration of the batch; a file
+            // truncated by another process while mapped is outside what the tool supports.
+            if let Ok(map) = unsafe { memmap2::Mmap::map(&file) } {
+                return Ok(InputBytes::Mapped(map));
+            }
+        }
+        std::fs::read(path).map(InputBytes::Read)
+    }
+}
+
+impl AsRef<[u8]> for InputBytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl std::ops::Deref for InputBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            InputBytes::Mapped(map) => map,
+            InputBytes::Read(vec) => vec,
+        }
+    }
+}
+
+/// Decodes a non-RAW input, correcting plain CMYK JPEGs on the way.
+///
+/// The `image` crate converts 4-component JPEGs to RGB assuming the Adobe convention
+/// (inverted ink values, signalled by an APP14 segment). Scanner software often writes
+/// plain CMYK without that segment, which would come out color-inverted, so those files
+/// are decoded to raw CMYK and converted here instead.
+///
+/// # Arguments
+/// * `path` - Path to the image file on disk.
+/// * `mmap` - Read the file through a memory map (`--mmap`).
+///
+/// # Returns
+/// * `anyhow::Result<DynamicImage>` - The decoded image or a decoding error.
+fn decode_standard(path: &Path, mmap: bool) -> anyhow::Result<DynamicImage> {
+    let is_jpeg = path.extension()
+        .map(|e| e.eq_ignore_ascii_case("jpg") || e.eq_ignore_ascii_case("jpeg"))
+        .unwrap_or(false);
+    if !is_jpeg {
+        if mmap {
+            let bytes = InputBytes::open(path, true)?;
+            return Ok(image::load_from_memory_with_format(&bytes, image::ImageFormat::from_path(path)?)?);
+        }
+        return Ok(image::open(path)?);
+    }
+
+    let bytes = InputBytes::open(path, mmap)?;
+    match sniff_jpeg(&bytes) {
+        Some(JpegLayout { components: 4, adobe: false }) => decode_plain_cmyk_jpeg(&bytes),
+        _ => Ok(image::load_from_memory_with_format(&bytes, image::ImageFormat::Jpeg)?),
+    }
+}
+
+/// Seekable source of page data: a buffered file, or a memory map with `--mmap`.
+trait PageInput: std::io::Read + std::io::Seek {}
+
+impl<T: std::io::Read + std::io::Seek> PageInput for T {}
+
+/// Reader for the pages after the first of a multi-page TIFF or animated GIF.
+///
+/// The first page always comes from `decode_standard`, so single-frame files decode
+/// exactly as they would without multi-page support. Later pages are decoded one at a
+/// time, on demand, so long scans and animations never sit in memory at once.
+struct PageReader {
+    /// Number of pages, including the first.
+    count: usize,
+    source: PageSource,
+}
+
+/// Where `PageReader` takes its remaining pages from.
+enum PageSource {
+    Tiff(Box<tiff::decoder::Decoder<Box<dyn PageInput>>>),
+    /// Composited frames, positioned after the first.
+    Gif(image::Frames<'static>),
+}
+
+impl PageReader {
+    /// Opens `path` if it is a TIFF or GIF with more than one page; `None` otherwise.
+    ///
+    /// Only headers are read to count pages: the TIFF directory chain, or the GIF block
+    /// structure without decompressing any frame.
+    fn open(path: &Path, mmap: bool) -> anyhow::Result<Option<PageReader>> {
+        let ext = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+        if !matches!(ext.as_str(), "tif" | "tiff" | "gif") {
+            return Ok(None);
+        }
+        let mut input: Box<dyn PageInput> = if mmap {
+            Box::new(std::io::Cursor::new(InputBytes::open(path, true)?))
+        } else {
+            Box::new(std::io::BufReader::new(std::fs::File::open(path)?))
+        };
+
+        if ext == "gif" {
+            use image::AnimationDecoder;
+            let count = count_gif_frames(&mut input)?;
+            if count <= 1 {
+                return Ok(None);
+            }
+            input.seek(std::io::SeekFrom::Start(0))?;
+            // Later frames are composited onto earlier ones, so the first is decoded again here
+            let mut frames = image::codecs::gif::GifDecoder::new(std::io::BufReader::new(input))?.into_frames();
+            frames.next().transpose()?;
+            return Ok(Some(PageReader { count, source: PageSource::Gif(frames) }));
+        }
+
+        let mut decoder = tiff::decoder::Decoder::new(input)?;
+        let mut count = 1;
+        while decoder.more_images() {
+            decoder.next_image()?;
+            count += 1;
+        }
+        if count == 1 {
+            return Ok(None);
+        }
+        decoder.seek_to_image(1)?;
+        Ok(Some(PageReader { count, source: PageSource::Tiff(Box::new(decoder)) }))
+    }
+
+    /// Decodes the next page, starting from the second. A page that fails to decode
+    /// does not stop the reader; the following call moves on to the page after it.
+    fn next_page(&mut self) -> anyhow::Result<DynamicImage> {
+        match &mut self.source {
+            PageSource::Tiff(decoder) => {
+                let page = decode_tiff_page(decoder);
+                if decoder.more_images() {
+                    decoder.next_image()?;
+                }
+                page
+            },
+            PageSource::Gif(frames) => match frames.next() {
+                Some(frame) => Ok(DynamicImage::ImageRgba8(frame?.into_buffer())),
+                None => anyhow::bail!("GIF ended before its last frame"),
+            },
+        }
+    }
+}
+
+/// Counts the frames of a GIF by walking its block structure; no image data is decoded.
+fn count_gif_frames(r: &mut impl std::io::Read) -> std::io::Result<usize> {
+    fn byte(r: &mut impl std::io::Read) -> std::io::Result<u8> {
+        let mut b = [0u8; 1];
+        r.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+    fn skip(r: &mut impl std::io::Read, n: u64) -> std::io::Result<()> {
+        if std::io::copy(&mut std::io::Read::take(&mut *r, n), &mut std::io::sink())? < n {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+    fn skip_sub_blocks(r: &mut impl std::io::Read) -> std::io::Result<()> {
+        loop {
+            match byte(r)? {
+                0 => return Ok(()),
+                len => skip(r, len as u64)?,
+            }
+        }
+    }
+    // Color tables hold 2^(n+1) RGB entries when their flag bit is set
+    let table_size = |flags: u8| if flags & 0x80 != 0 { 3u64 << ((flags & 0x07) + 1) } else { 0 };
+
+    // Signature, version and logical screen descriptor
+    let mut header = [0u8; 13];
+    r.read_exact(&mut header)?;
+    if &header[..3] != b"GIF" {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "not a GIF"));
+    }
+    skip(r, table_size(header[10]))?;
+
+    let mut frames = 0;
+    loop {
+        match byte(r)? {
+            // Extension: label, then data sub-blocks
+            0x21 => {
+                byte(r)?;
+                skip_sub_blocks(r)?;
+            },
+            // Image descriptor, optional local color table, LZW code size, data sub-blocks
+            0x2C => {
+                let mut descriptor = [0u8; 9];
+                r.read_exact(&mut descriptor)?;
+                skip(r, table_size(descriptor[8]))?;
+                byte(r)?;
+                skip_sub_blocks(r)?;
+                frames += 1;
+            },
+            0x3B => return Ok(frames),
+            other => {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unexpected GIF block 0x{:02x}", other)));
+            },
+        }
+    }
+}
+
+/// Decodes the current page of a TIFF, covering the layouts `image` itself supports
+/// for scans: bilevel, grayscale, RGB and CMYK, with or without alpha, at 8 or 16 bits.
+/// CMYK is converted to RGB with the same formula `image` uses for the first page.
+fn decode_tiff_page(decoder: &mut tiff::decoder::Decoder<Box<dyn PageInput>>) -> anyhow::Result<DynamicImage> {
+    use tiff::decoder::DecodingResult;
+    use tiff::ColorType;
+    let (width, height) = decoder.dimensions()?;
+    let color = decoder.colortype()?;
+    let page = match (color, decoder.read_image()?) {
+        (ColorType::Gray(1), DecodingResult::U8(packed)) => {
+            // Rows are packed MSB-first and padded to whole bytes
+            let row_bytes = (width as usize).div_ceil(8);
+            let pixels: Vec<u8> = packed
+                .chunks_exact(row_bytes)
+                .flat_map(|row| (0..width as usize).map(move |x| if row[x / 8] & (0x80 >> (x % 8)) != 0 { 255 } else { 0 }))
+                .collect();
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8)
+        },
+        (ColorType::Gray(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma8),
+        (ColorType::Gray(16), DecodingResult::U16(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma16),
+        (ColorType::GrayA(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA8),
+        (ColorType::GrayA(16), DecodingResult::U16(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA16),
+        (ColorType::RGB(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8),
+        (ColorType::RGB(16), DecodingResult::U16(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb16),
+        (ColorType::RGBA(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8),
+        (ColorType::RGBA(16), DecodingResult::U16(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba16),
+        (ColorType::CMYK(8), DecodingResult::U8(data)) => {
+            let rgb: Vec<u8> = data.chunks_exact(4).flat_map(|px| {
+                let k = 1.0 - px[3] as f32 / 255.0;
+                [0, 1, 2].map(|i| ((255.0 - px[i] as f32) * k) as u8)
+            }).collect();
+            ImageBuffer::from_raw(width, height, rgb).map(DynamicImage::ImageRgb8)
+        },
+        (ColorType::CMYK(16), DecodingResult::U16(data)) => {
+            let rgb: Vec<u16> = data.chunks_exact(4).flat_map(|px| {
+                let k = 1.0 - px[3] as f32 / 65535.0;
+                [0, 1, 2].map(|i| ((65535.0 - px[i] as f32) * k) as u16)
+            }).collect();
+            ImageBuffer::from_raw(width, height, rgb).map(DynamicImage::ImageRgb16)
+        },
+        (color, _) => anyhow::bail!("unsupported TIFF page color type {:?}", color),
+    };
+    page.ok_or_else(|| anyhow::anyhow!("TIFF page data does not match its {}x{} size", width, height))
+}
+
+/// Decodes a non-inverted CMYK JPEG and converts it to RGB.
+///
+/// Uses the naive subtractive model `R = (255 - C) * (255 - K) / 255` (likewise for G/B),
+/// which matches what most viewers do in the absence of an embedded ICC profile.
+fn decode_plain_cmyk_jpeg(bytes: &[u8]) -> anyhow::Result<DynamicImage> {
+    let options = zune_core::options::DecoderOptions::default()
+        .jpeg_set_out_colorspace(zune_core::colorspace::ColorSpace::CMYK)
+        .set_strict_mode(false)
+        .set_max_width(usize::MAX)
+        .set_max_height(usize::MAX);
+    let mut decoder = zune_jpeg::JpegDecoder::new_with_options(zune_core::bytestream::ZCursor::new(bytes), options);
+    let cmyk = decoder.decode().map_err(|e| anyhow::anyhow!("CMYK JPEG decode failed: {:?}", e))?;
+    let info = decoder.info().ok_or_else(|| anyhow::anyhow!("CMYK JPEG is missing header info"))?;
+
+    let mut rgb = vec![0u8; cmyk.len() / 4 * 3];
+    rgb.par_chunks_exact_mut(3)
+        .zip(cmyk.par_chunks_exact(4))
+        .for_each(|(out, px)| {
+            let k = 255 - px[3] as u32;
+            for channel in 0..3 {
+                out[channel] = ((255 - px[channel] as u32) * k / 255) as u8;
+            }
+        });
+
+    let img = ImageBuffer::<Rgb<u8>, _>::from_raw(info.width as u32, info.height as u32, rgb)
+        .ok_or_else(|| anyhow::anyhow!("Failed to create image buffer"))?;
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+/// Applies a chain of visual filters and adjustments to an image.
+///
+/// To optimize cache locality and reduce memory iterations, primary color 
+/// adjustments (Brightness, Contrast, Saturation) are fused into a single 
+/// parallelized pass over the pixel buffer. Grayscale sources skip the saturation
+/// step, since their channels are already equal and it would be a costly no-op.
+///
+/// Sources with an alpha channel are processed as `Rgba8`; every stage adjusts color
+/// only and carries the original transparency through to the output.
+///
+/// # Pipeline Order
+/// 0. Deskew (rotation), so every later stage sees the straightened image, then chroma
+///    key, so the backdrop is matched on its original colors.
+/// 1. Fused white balance (per image, or the `--normalize-batch` reference) / levels / curves / brightness / contrast / saturation / hue / color matrix / 3D LUT / posterize / invert pass.
+///    With a `mask`, the brightness/contrast/saturation result is blended per pixel with
+///    the tones before those steps.
+/// 2. Median denoise.
+/// 3. Gaussian blur.
+/// 4. Adaptive threshold (replacing the image, or as an alpha mask with `threshold_as_mask`).
+/// 5. Border, which enlarges the canvas.
+///
+/// Spatial filters do not commute: denoising before blurring, or blurring before
+/// thresholding, gives a visibly different result than the reverse, so the order above
+/// is fixed.
+///
+/// # Arguments
+/// * `img` - The source `DynamicImage`.
+/// * `options` - A reference to the `ProcessOptions` to apply.
+/// * `lut` - The parsed `options.lut`, loaded once per batch by the caller.
+/// * `mask` - The loaded `options.mask`, at any size; it is resized to the image here.
+/// * `batch` - The `--normalize-batch` reference; when set it replaces `auto_white_balance`.
+///
+/// # Returns
+/// * `DynamicImage` - The modified image.
+fn apply_filters(img: DynamicImage, options: &ProcessOptions, lut: Option<&CubeLut>, mask: Option<&image::GrayImage>, batch: Option<&BatchReference>) -> DynamicImage {
+    let is_grayscale = !img.color().has_color();
+    // Chroma keying needs an alpha channel to write the matte into
+    let has_alpha = img.color().has_alpha() || options.chroma_key.is_some();
+    let high_precision = img.color().bytes_per_pixel() > img.color().channel_count();
+    let mut final_img = if options.dither && high_precision {
+        dither_to_8bit(&img, has_alpha)
+    } else if has_alpha {
+        DynamicImage::ImageRgba8(img.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(img.to_rgb8())
+    };
+    
+    if options.deskew {
+        if let Some(angle) = detect_skew(&final_img, options.adaptive_threshold) {
+            final_img = rotate_image(final_img, angle);
+        }
+    }
+
+    if let (Some(key), DynamicImage::ImageRgba8(rgba)) = (&options.chroma_key, &mut final_img) {
+        rgba.par_chunks_exact_mut(4).for_each(|pixel| {
+            let coverage = key.coverage(&pixel[..3]);
+            pixel[3] = (pixel[3] as f32 * coverage).round() as u8;
+        });
+    }
+
+    let s = if is_grayscale { 1.0 } else { options.saturation };
+
+    let white_balance = if let Some(reference) = batch {
+        Some(if is_grayscale { [reference.exposure; 3] } else { reference.multipliers })
+    } else if options.auto_white_balance && !is_grayscale {
+        let channels = if has_alpha { 4 } else { 3 };
+        gray_world_gains(final_img.as_bytes(), channels)
+    } else {
+        None
+    };
+    const IDENTITY: [f32; 9] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+    let mixer = options.color_matrix.filter(|m| *m != IDENTITY);
+    let levels = options.levels.map(|l| l.lut());
+    let curves = options.curves.as_ref().map(Curves::luts);
+    let posterize = options.posterize_levels.map(posterize_lut);
+    let hue = if is_grayscale || options.hue_degrees == 0.0 {
+        None
+    } else {
+        Some(hue_rotation_matrix(options.hue_degrees))
+    };
+
+    // Multi-adjust pass: Processes pixel channels in a single parallel iteration.
+    // Only the RGB channels are touched; alpha (when present) passes through unchanged.
+    if options.brightness != 0.0 || options.contrast != 1.0 || s != 1.0 || hue.is_some() || mixer.is_some() || white_balance.is_some() || lut.is_some() || levels.is_some() || curves.is_some() || posterize.is_some() || options.invert {
+        let b = options.brightness * 255.0;
+        let c = options.contrast;
+        let adjust_bc = options.brightness != 0.0 || options.contrast != 1.0;
+        let (width, height) = (final_img.width(), final_img.height());
+        let mask = mask.filter(|_| adjust_bc || s != 1.0).map(|m| {
+            if m.dimensions() == (width, height) {
+                std::borrow::Cow::Borrowed(m)
+            } else {
+                std::borrow::Cow::Owned(image::imageops::resize(m, width, height, image::imageops::FilterType::Triangle))
+            }
+        });
+        let (buf, channels) = match &mut final_img {
+            DynamicImage::ImageRgba8(rgba) => (&mut **rgba, 4),
+            DynamicImage::ImageRgb8(rgb) => (&mut **rgb, 3),
+            _ => unreachable!("filter buffer is always Rgb8 or Rgba8"),
+        };
+        
+        // Work in chunks so the brightness/contrast stage can run on whole SIMD vectors;
+        // every stage still sees each pixel in the same order as a per-pixel loop would.
+        buf.par_chunks_mut(channels * FUSED_CHUNK_PIXELS).enumerate().for_each(|(chunk_index, chunk)| {
+            for pixel in chunk.chunks_exact_mut(channels) {
+                // White balance comes first so every later step sees neutral colors
+                if let Some(gains) = &white_balance {
+                    for (v, gain) in pixel[..3].iter_mut().zip(gains) {
+                        *v = (*v as f32 * gain).clamp(0.0, 255.0) as u8;
+                    }
+                }
+
+                // Levels remap runs first so the other adjustments see the corrected tonal range
+                if let Some(lut) = &levels {
+                    for v in pixel[..3].iter_mut() {
+                        *v = lut[*v as usize];
+                    }
+                }
+
+                // Curves refine the levels result before the linear adjustments
+                if let Some(luts) = &curves {
+                    for (v, lut) in pixel[..3].iter_mut().zip(luts) {
+                        *v = lut[*v as usize];
+                    }
+                }
+            }
+
+            // A mask blends the next two steps against the tones they started from
+            let mut before = [0u8; 4 * FUSED_CHUNK_PIXELS];
+            if mask.is_some() {
+                before[..chunk.len()].copy_from_slice(chunk);
+            }
+
+            // Fused Brightness & Contrast: (v - 128) * c + 128 + b
+            if adjust_bc {
+                brightness_contrast_simd(chunk, channels, b, c);
+            }
+
+            for (i, pixel) in chunk.chunks_exact_mut(channels).enumerate() {
+                // Perceptual saturation adjustment using standard ITU-R 601 luma weights
+                if s != 1.0 {
+                    let r = pixel[0] as f32;
+                    let g = pixel[1] as f32;
+                    let b = pixel[2] as f32;
+                    let l = 0.299 * r + 0.587 * g + 0.114 * b;
+                    pixel[0] = (l + (r - l) * s).clamp(0.0, 255.0) as u8;
+                    pixel[1] = (l + (g - l) * s).clamp(0.0, 255.0) as u8;
+                    pixel[2] = (l + (b - l) * s).clamp(0.0, 255.0) as u8;
+                }
+
+                // Localized adjustment: weight the change by the mask at this pixel's (x, y)
+                if let Some(mask) = &mask {
+                    let index = chunk_index * FUSED_CHUNK_PIXELS + i;
+                    let (x, y) = ((index % width as usize) as u32, (index / width as usize) as u32);
+                    let weight = mask.get_pixel(x, y)[0] as f32 / 255.0;
+                    let original = &before[i * channels..i * channels + 3];
+                    for (v, o) in pixel[..3].iter_mut().zip(original) {
+                        let o = *o as f32;
+                        *v = (o + (*v as f32 - o) * weight).round() as u8;
+                    }
+                }
+
+                // Hue rotation around the luma axis
+                if let Some(m) = &hue {
+                    let r = pixel[0] as f32;
+                    let g = pixel[1] as f32;
+                    let b = pixel[2] as f32;
+                    pixel[0] = (m[0] * r + m[1] * g + m[2] * b).clamp(0.0, 255.0) as u8;
+                    pixel[1] = (m[3] * r + m[4] * g + m[5] * b).clamp(0.0, 255.0) as u8;
+                    pixel[2] = (m[6] * r + m[7] * g + m[8] * b).clamp(0.0, 255.0) as u8;
+                }
+
+                // Channel mixer
+                if let Some(m) = &mixer {
+                    let r = pixel[0] as f32;
+                    let g = pixel[1] as f32;
+                    let b = pixel[2] as f32;
+                    pixel[0] = (m[0] * r + m[1] * g + m[2] * b).clamp(0.0, 255.0) as u8;
+                    pixel[1] = (m[3] * r + m[4] * g + m[5] * b).clamp(0.0, 255.0) as u8;
+                    pixel[2] = (m[6] * r + m[7] * g + m[8] * b).clamp(0.0, 255.0) as u8;
+                }
+
+                // Color grade through the 3D LUT
+                if let Some(cube) = lut {
+                    let graded = cube.apply([pixel[0], pixel[1], pixel[2]]);
+                    pixel[..3].copy_from_slice(&graded);
+                }
+
+                // Posterization: quantize each channel through the precomputed table
+                if let Some(lut) = &posterize {
+                    for v in pixel[..3].iter_mut() {
+                        *v = lut[*v as usize];
+                    }
+                }
+
+                // Negative: applied last so the other adjustments act on the original tones
+                if options.invert {
+                    for v in pixel[..3].iter_mut() {
+                        *v = 255 - *v;
+                    }
+                }
+            }
+        });
+    }
+
+    // Apply optional Denoising (3x3 Median Filter)
+    if options.denoise {
+        final_img = match final_img {
+            DynamicImage::ImageRgba8(rgba_inner) => {
+                // Median-filtering alpha would erode thin opaque edges, so restore the original mask.
+                let mut denoised = imageproc::filter::median_filter(&rgba_inner, 1, 1);
+                restore_alpha(&mut denoised, &rgba_inner);
+                DynamicImage::ImageRgba8(denoised)
+            },
+            DynamicImage::ImageRgb8(rgb_inner) => {
+                DynamicImage::ImageRgb8(imageproc::filter::median_filter(&rgb_inner, 1, 1))
+            },
+            other => other,
+        };
+    }
+
+    // Apply optional Gaussian Blur
+    if let Some(sigma) = options.blur_sigma {
+        final_img = match final_img {
+            DynamicImage::ImageRgba8(rgba_inner) => {
+                let mut blurred = image::imageops::blur(&rgba_inner, sigma);
+                restore_alpha(&mut blurred, &rgba_inner);
+                DynamicImage::ImageRgba8(blurred)
+            },
+            other => other.blur(sigma),
+        };
+    }
+
+    // Apply optional Adaptive Thresholding for high-contrast/document-style output
+    if options.adaptive_threshold {
+        let luma = final_img.to_luma8();
+        let radius = options.adaptive_threshold_radius.unwrap_or(DEFAULT_THRESHOLD_RADIUS);
+        let mut thresholded = imageproc::contrast::adaptive_threshold(&luma, radius);
+        if options.adaptive_threshold_invert {
+            image::imageops::invert(&mut thresholded);
+        }
+        final_img = if options.threshold_as_mask {
+            // Cutout: the color image survives where the mask is white, on top of any source alpha
+            let mut rgba = final_img.to_rgba8();
+            for (out, t) in rgba.pixels_mut().zip(thresholded.pixels()) {
+                out[3] = ((out[3] as u16 * t[0] as u16) / 255) as u8;
+            }
+            DynamicImage::ImageRgba8(rgba)
+        } else if has_alpha {
+            // Keep the source transparency by pairing the B&W result with the original alpha.
+            let mut luma_alpha = final_img.to_luma_alpha8();
+            for (out, t) in luma_alpha.pixels_mut().zip(thresholded.pixels()) {
+                out[0] = t[0];
+            }
+            DynamicImage::ImageLumaA8(luma_alpha)
+        } else {
+            DynamicImage::ImageLuma8(thresholded)
+        };
+    }
+
+    if let Some(border) = &options.border {
+        final_img = add_border(final_img, border);
+    }
+
+    final_img
+}
+
+/// Loads every distinct file that `path` picks out of the global or per-file options,
+/// once each, for jobs to share. Fails on the first file `load` rejects.
+///
+/// Each file's content hash goes into `hashes`, so `content_hash` can tell when a LUT or
+/// mask was edited in place.
+fn load_shared<T>(
+    global: &ProcessOptions,
+    jobs: &[InputJob],
+    path: fn(&ProcessOptions) -> Option<&PathBuf>,
+    load: fn(&Path) -> anyhow::Result<T>,
+    hashes: &mut std::collections::HashMap<PathBuf, blake3::Hash>,
+) -> anyhow::Result<std::collections::HashMap<PathBuf, Arc<T>>> {
+    let mut loaded = std::collections::HashMap::new();
+    for file in std::iter::once(global).chain(jobs.iter().filter_map(|job| job.options.as_ref())).filter_map(path) {
+        if !loaded.contains_key(file) {
+            loaded.insert(file.clone(), Arc::new(load(file)?));
+            let mut hasher = blake3::Hasher::new();
+            hasher.update_reader(std::fs::File::open(file)?)?;
+            hashes.insert(file.clone(), hasher.finalize());
+        }
+    }
+    Ok(loaded)
+}
+
+/// Reads a `mask` image as 8-bit grayscale.
+fn load_mask(path: &Path) -> anyhow::Result<image::GrayImage> {
+    let mask = image::open(path).map_err(|e| anyhow::anyhow!("failed to read mask {}: {}", path.display(), e))?;
+    Ok(mask.to_luma8())
+}
+
+/// Pastes `img` centered on a canvas enlarged by `border.width_px` on every side.
+fn add_border(img: DynamicImage, border: &Border) -> DynamicImage {
+    let [r, g, b] = border.color;
+    let luma = (0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32).round() as u8;
+    let w = border.width_px;
+    match img {
+        DynamicImage::ImageRgb8(inner) => DynamicImage::ImageRgb8(framed(&inner, w, Rgb([r, g, b]))),
+        DynamicImage::ImageRgba8(inner) => DynamicImage::ImageRgba8(framed(&inner, w, image::Rgba([r, g, b, 255]))),
+        DynamicImage::ImageLuma8(inner) => DynamicImage::ImageLuma8(framed(&inner, w, image::Luma([luma]))),
+        DynamicImage::ImageLumaA8(inner) => DynamicImage::ImageLumaA8(framed(&inner, w, image::LumaA([luma, 255]))),
+        other => other,
+    }
+}
+
+fn framed<P: image::Pixel>(img: &ImageBuffer<P, Vec<P::Subpixel>>, width: u32, fill: P) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let mut canvas = ImageBuffer::from_pixel(img.width() + 2 * width, img.height() + 2 * width, fill);
+    image::imageops::replace(&mut canvas, img, width as i64, width as i64);
+    canvas
+}
+
+/// Computes gray-world white-balance gains from a pre-pass over the RGB channels.
+///
+/// Each gain scales its channel mean onto the mean of all three (clamped to
+/// `1 / AWB_MAX_GAIN ..= AWB_MAX_GAIN`). Fully transparent pixels are ignored. Returns
+/// `None` when there is nothing to average, or when the image is near-monochrome: its
+/// opponent-color chroma vectors point the same way (coherence of at least
+/// `AWB_MONOCHROME_COHERENCE`), as in sepia prints or close-ups of one colored surface.
+fn gray_world_gains(buf: &[u8], channels: usize) -> Option<[f32; 3]> {
+    // [sum r, sum g, sum b, sum chroma a, sum chroma b, sum chroma magnitude, count]
+    // Fixed-size blocks summed in order, so the inexact magnitude sum does not depend on
+    // how Rayon happened to split the work (see `--deterministic`)
+    let sums = buf
+        .par_chunks(channels * FUSED_CHUNK_PIXELS)
+        .map(|block| {
+            block
+                .chunks_exact(channels)
+                .filter(|pixel| channels < 4 || pixel[3] > 0)
+                .fold([0f64; 7], |mut acc, pixel| {
+                    let (r, g, b) = (pixel[0] as f64, pixel[1] as f64, pixel[2] as f64);
+                    let ca = r - g;
+                    let cb = (r + g) / 2.0 - b;
+                    acc[0] += r;
+                    acc[1] += g;
+                    acc[2] += b;
+                    acc[3] += ca;
+                    acc[4] += cb;
+                    acc[5] += (ca * ca + cb * cb).sqrt();
+                    acc[6] += 1.0;
+                    acc
+                })
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .fold([0f64; 7], |mut a, b| {
+            for (x, y) in a.iter_mut().zip(b) {
+                *x += y;
+            }
+            a
+        });
+
+    let count = sums[6];
+    if count == 0.0 || sums[5] == 0.0 {
+        return None;
+    }
+    let coherence = (sums[3] * sums[3] + sums[4] * sums[4]).sqrt() / sums[5];
+    if coherence as f32 >= AWB_MONOCHROME_COHERENCE {
+        return None;
+    }
+
+    let means = [sums[0] / count, sums[1] / count, sums[2] / count];
+    let gray = (means[0] + means[1] + means[2]) / 3.0;
+    if means.iter().any(|&m| m < 1.0) {
+        return None;
+    }
+    Some(means.map(|m| ((gray / m) as f32).clamp(1.0 / AWB_MAX_GAIN, AWB_MAX_GAIN)))
+}
+
+/// Estimates the skew of a document scan in degrees (positive is clockwise).
+///
+/// Runs Canny edge detection and a Hough transform on a downscaled grayscale copy and
+/// takes the median angle of the detected near-horizontal lines. Returns `None` when no
+/// lines are found or the skew rounds to zero, so straight scans are never resampled,
+/// and for images that do not look like documents unless `is_document` vouches for them.
+fn detect_skew(img: &DynamicImage, is_document: bool) -> Option<f32> {
+    let luma = if img.width().max(img.height()) > DESKEW_ANALYSIS_EDGE {
+        img.thumbnail(DESKEW_ANALYSIS_EDGE, DESKEW_ANALYSIS_EDGE).to_luma8()
+    } else {
+        img.to_luma8()
+    };
+    if !is_document && !looks_like_document(&luma) {
+        return None;
+    }
+    let edges = imageproc::edges::canny(&luma, 50.0, 100.0);
+    let lines = imageproc::hough::detect_lines(&edges, imageproc::hough::LineDetectionOptions {
+        vote_threshold: (luma.width() / 4).max(1),
+        suppression_radius: 8,
+    });
+
+    // Hough angles describe the line normal, so horizontal lines sit at 90 degrees
+    let mut skews: Vec<f32> = lines
+        .iter()
+        .map(|line| line.angle_in_degrees as f32 - 90.0)
+        .filter(|skew| skew.abs() <= DESKEW_MAX_DEGREES)
+        .collect();
+    if skews.is_empty() {
+        return None;
+    }
+    skews.sort_by(|a, b| a.total_cmp(b));
+    let skew = skews[skews.len() / 2];
+    (skew != 0.0).then_some(skew)
+}
+
+/// Document heuristic for `deskew`: a bimodal luma histogram.
+///
+/// Splits the tones at the Otsu level and requires the two sides' means to be at least
+/// `DESKEW_MIN_CONTRAST` apart, with `DESKEW_DOCUMENT_SHARE` of the pixels within
+/// `DESKEW_TONE_SPREAD` of their side's mean. Text on paper passes; photos, whose tones
+/// spread over the whole range, do not.
+fn looks_like_document(luma: &image::GrayImage) -> bool {
+    let mut histogram = [0u64; 256];
+    for v in luma.iter() {
+        histogram[*v as usize] += 1;
+    }
+    let split = imageproc::contrast::otsu_level(luma) as usize;
+    let mean = |range: std::ops::Range<usize>| {
+        let (sum, count) = range.fold((0u64, 0u64), |(sum, count), v| (sum + v as u64 * histogram[v], count + histogram[v]));
+        (count > 0).then(|| sum as f32 / count as f32)
+    };
+    let (Some(ink), Some(paper)) = (mean(0..split + 1), mean(split + 1..256)) else {
+        return false;
+    };
+    if paper - ink < DESKEW_MIN_CONTRAST {
+        return false;
+    }
+    let near: u64 = (0..256)
+        .filter(|&v| (v as f32 - if v <= split { ink } else { paper }).abs() <= DESKEW_TONE_SPREAD)
+        .map(|v| histogram[v])
+        .sum();
+    near as f32 >= DESKEW_DOCUMENT_SHARE * luma.len() as f32
+}
+
+/// Rotates an `Rgb8`/`Rgba8` image about its center by `-degrees`, undoing a detected skew.
+/// Uncovered corners are filled white (opaque) or transparent (with alpha).
+fn rotate_image(img: DynamicImage, degrees: f32) -> DynamicImage {
+    use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
+    let theta = -degrees.to_radians();
+    match img {
+        DynamicImage::ImageRgba8(rgba) => {
+            DynamicImage::ImageRgba8(rotate_about_center(&rgba, theta, Interpolation::Bilinear, image::Rgba([255, 255, 255, 0])))
+        },
+        DynamicImage::ImageRgb8(rgb) => {
+            DynamicImage::ImageRgb8(rotate_about_center(&rgb, theta, Interpolation::Bilinear, Rgb([255, 255, 255])))
+        },
+        other => other,
+    }
+}
+
+/// Builds the row-major 3x3 hue-rotation matrix for the given angle.
+///
+/// Uses the standard luminance-preserving formulation (as in SVG `feColorMatrix`
+/// `hueRotate`): gray pixels are unchanged and 0 degrees yields the identity.
+fn hue_rotation_matrix(degrees: f32) -> [f32; 9] {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    [
+        0.213 + cos * 0.787 - sin * 0.213,
+        0.715 - cos * 0.715 - sin * 0.715,
+        0.072 - cos * 0.072 + sin * 0.928,
+        0.213 - cos * 0.213 + sin * 0.143,
+        0.715 + cos * 0.285 + sin * 0.140,
+        0.072 - cos * 0.072 - sin * 0.283,
+        0.213 - cos * 0.213 - sin * 0.787,
+        0.715 - cos * 0.715 + sin * 0.715,
+        0.072 + cos * 0.928 + sin * 0.072,
+    ]
+}
+
+/// Builds a lookup table mapping 0-255 onto `levels` evenly spaced output values.
+///
+/// Each input snaps to the nearest level, and the levels always include 0 and 255, so a
+/// full-range channel yields exactly `levels` distinct values.
+fn posterize_lut(levels: u8) -> [u8; 256] {
+    let steps = (levels.max(2) - 1) as f32;
+    let mut lut = [0u8; 256];
+    for (v, out) in lut.iter_mut().enumerate() {
+        let level = (v as f32 * steps / 255.0).round();
+        *out = (level * 255.0 / steps).round() as u8;
+    }
+    lut
+}
+
+/// Reduces a 16-bit or floating-point image to `Rgb8`/`Rgba8` with Floyd-Steinberg dithering.
+fn dither_to_8bit(img: &DynamicImage, has_alpha: bool) -> DynamicImage {
+    let (width, height) = (img.width(), img.height());
+    let channels = if has_alpha { 4 } else { 3 };
+    let samples = if has_alpha { img.to_rgba32f().into_raw() } else { img.to_rgb32f().into_raw() };
+    let scaled = samples.into_iter().map(|v| v * 255.0).collect();
+    let vec = floyd_steinberg(scaled, width as usize, channels);
+    if has_alpha {
+        DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, vec).expect("buffer sized from source image"))
+    } else {
+        DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, vec).expect("buffer sized from source image"))
+    }
+}
+
+/// Copies the alpha channel of `src` onto `out`, undoing spatial filtering of transparency.
+fn restore_alpha(out: &mut image::RgbaImage, src: &image::RgbaImage) {
+    for (o, s) in out.pixels_mut().zip(src.pixels()) {
+        o[3] = s[3];
+    }
+}
+
+/// Builds the batch-wide `ProcessOptions` from an optional config file and optional
+/// `--options` JSON (inline, or `@path` to a JSON file).
+///
+/// Both sources are read as JSON objects and merged field by field, with `explicit`
+/// winning over `config`; fields absent from both fall back to their serde defaults.
+/// The merged result is validated, so a bad value in either source is rejected.
+fn load_options(config: Option<&str>, explicit: Option<&str>) -> anyhow::Result<ProcessOptions> {
+    let mut merged = serde_json::Map::new();
+    if let Some(path) = config {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read config {}: {}", path, e))?;
+        let defaults: serde_json::Map<String, serde_json::Value> = if path.to_lowercase().ends_with(".toml") {
+            toml::from_str(&text).map_err(|e| anyhow::anyhow!("config {}: {}", path, e))?
+        } else {
+            serde_json::from_str(&text).map_err(|e| anyhow::anyhow!("config {}: {}", path, e))?
+        };
+        merged.extend(defaults);
+    }
+    if let Some(arg) = explicit {
+        let json = match arg.strip_prefix('@') {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("failed to read options file {}: {}", path, e))?,
+            None => arg.to_string(),
+        };
+        let overrides: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&json)
+            .map_err(|e| anyhow::anyhow!("--options: {}", e))?;
+        merged.extend(overrides);
+    }
+    let options: ProcessOptions = serde_json::from_value(serde_json::Value::Object(merged))?;
+    options.validate()?;
+    Ok(options)
+}
+
+/// Resolves `--inputs` into jobs: raw string lists or JSON manifests (path arrays or
+/// per-file option objects).
+///
+/// A value naming an existing `.json` file is loaded as a manifest; anything else is
+/// split on commas, skipping blank entries. Either way the result may be empty.
+fn resolve_inputs(inputs: &str, options: &ProcessOptions) -> anyhow::Result<Vec<InputJob>> {
+    if inputs.ends_with(".json") && Path::new(inputs).exists() {
+        return load_manifest(inputs, options);
+    }
+    Ok(inputs.split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(|s| InputJob { path: s.to_string(), options: None, sequence: None })
+        .collect())
+}
+
+/// Rejects an empty batch with "no inputs".
+///
+/// An empty batch is almost always a caller mistake (empty list or manifest, bad filter);
+/// fail loudly rather than reporting a vacuous success.
+fn require_inputs(jobs: Vec<InputJob>) -> anyhow::Result<Vec<InputJob>> {
+    if jobs.is_empty() {
+        anyhow::bail!("no inputs");
+    }
+    Ok(jobs)
+}
+
+/// Loads a JSON manifest in either of its two supported shapes.
+///
+/// - Legacy: an array of path strings, all processed with the global options.
+/// - Extended: an array of `ManifestEntry` objects carrying per-file option overrides.
+///
+/// The format is chosen by peeking at the first token inside the top-level array, so
+/// an empty array is treated as the legacy form. Merged per-file options are validated
+/// here, so a bad override is reported before processing starts.
+fn load_manifest(path: &str, global: &ProcessOptions) -> anyhow::Result<Vec<InputJob>> {
+    let text = std::fs::read_to_string(path)?;
+    let first_token = text.trim_start()
+        .strip_prefix('[')
+        .and_then(|rest| rest.trim_start().chars().next());
+
+    if first_token != Some('{') {
+        let paths: Vec<String> = serde_json::from_str(&text)?;
+        return Ok(paths.into_iter().map(|path| InputJob { path, options: None, sequence: None }).collect());
+    }
+
+    let entries: Vec<ManifestEntry> = serde_json::from_str(&text)?;
+    entries.into_iter().map(|entry| {
+        let options = match entry.options {
+            Some(overrides) => {
+                let mut merged = serde_json::to_value(global)?;
+                if let serde_json::Value::Object(ref mut fields) = merged {
+                    fields.extend(overrides);
+                }
+                let options: ProcessOptions = serde_json::from_value(merged)
+                    .map_err(|e| anyhow::anyhow!("manifest options for {}: {}", entry.path, e))?;
+                options.validate()
+                    .map_err(|e| anyhow::anyhow!("manifest options for {}: {}", entry.path, e))?;
+                Some(options)
+            },
+            None => None,
+        };
+        Ok(InputJob { path: entry.path, options, sequence: None })
+    }).collect()
+}
+
+/// Encodes `img` as JPEG in memory at `quality` with the given chroma subsampling.
+///
+/// The `image` encoder only writes 4:4:4, so subsampled output goes through
+/// `jpeg_encoder` instead; 4:4:4 keeps the `image` encoder so existing outputs are unchanged.
+fn encode_jpeg(img: &DynamicImage, quality: u8, subsampling: ChromaSubsampling) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let sampling = match subsampling {
+        ChromaSubsampling::Cs444 => {
+            image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buf, quality).encode_image(img)?;
+            return Ok(buf);
+        },
+        ChromaSubsampling::Cs422 => jpeg_encoder::SamplingFactor::R_4_2_2,
+        ChromaSubsampling::Cs420 => jpeg_encoder::SamplingFactor::R_4_2_0,
+    };
+    let width = u16::try_from(img.width()).map_err(|_| anyhow::anyhow!("image too wide for JPEG: {} px", img.width()))?;
+    let height = u16::try_from(img.height()).map_err(|_| anyhow::anyhow!("image too tall for JPEG: {} px", img.height()))?;
+    let mut encoder = jpeg_encoder::Encoder::new(&mut buf, quality);
+    encoder.set_sampling_factor(sampling);
+    match img {
+        DynamicImage::ImageLuma8(luma) => encoder.encode(luma.as_raw(), width, height, jpeg_encoder::ColorType::Luma)?,
+        other => encoder.encode(other.to_rgb8().as_raw(), width, height, jpeg_encoder::ColorType::Rgb)?,
+    }
+    Ok(buf)
+}
+
+/// Encodes `img` as JPEG at the highest quality whose output is at most `cap` bytes.
+///
+/// Binary-searches quality between `JPEG_MIN_QUALITY` and 100, encoding in memory each
+/// time. When nothing fits, the `JPEG_MIN_QUALITY` encode is returned as the closest result.
+fn encode_jpeg_within(img: &DynamicImage, cap: u64, subsampling: ChromaSubsampling) -> anyhow::Result<Vec<u8>> {
+    let encode = |quality: u8| encode_jpeg(img, quality, subsampling);
+
+    let (mut lo, mut hi) = (JPEG_MIN_QUALITY, 100u8);
+    let mut best = None;
+    while lo <= hi {
+        let quality = lo + (hi - lo) / 2;
+        let buf = encode(quality)?;
+        if buf.len() as u64 <= cap {
+            best = Some(buf);
+            lo = quality + 1;
+        } else if quality == JPEG_MIN_QUALITY {
+            break;
+        } else {
+            hi = quality - 1;
+        }
+    }
+    match best {
+        Some(buf) => Ok(buf),
+        None => encode(JPEG_MIN_QUALITY),
+    }
+}
+
+/// Encodes a processed image in memory as `format`, keeping JPEGs under `max_bytes` when set.
+fn encode_output(img: &DynamicImage, format: OutputFormat, max_bytes: Option<u64>, subsampling: ChromaSubsampling) -> anyhow::Result<Vec<u8>> {
+    match (format, max_bytes) {
+        (OutputFormat::Jpeg, Some(cap)) => return encode_jpeg_within(img, cap, subsampling),
+        (OutputFormat::Jpeg, None) => return encode_jpeg(img, JPEG_DEFAULT_QUALITY, subsampling),
+        _ => {},
+    }
+    let mut buf = Vec::new();
+    img.write_to(&mut std::io::Cursor::new(&mut buf), format.image_format())?;
+    Ok(buf)
+}
+
+/// File handed to the `--output-zip` writer thread: entry name, encoded bytes, and a
+/// channel on which the writer reports whether the entry was written.
+type ZipEntry = (String, Vec<u8>, std::sync::mpsc::Sender<std::io::Result<()>>);
+
+/// Archive writer for `--output-zip`.
+///
+/// `zip::ZipWriter` is not thread-safe, so Rayon workers encode into memory in parallel
+/// and send finished files through a bounded channel to one writer thread, which appends
+/// them in arrival order. Each worker waits for its entry's result so a failed write is
+/// reported against the right file.
+struct ZipSink {
+    /// Absolute path of the archive.
+    path: PathBuf,
+    sender: std::sync::mpsc::SyncSender<ZipEntry>,
+    writer: std::thread::JoinHandle<anyhow::Result<()>>,
+}
+
+impl ZipSink {
+    /// Creates (or truncates) the archive at `path` and starts the writer thread.
+    fn create(path: &Path) -> anyhow::Result<Self> {
+        let path = std::path::absolute(path)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = std::fs::File::create(&path)
+            .map_err(|e| anyhow::anyhow!("failed to create {}: {}", path.display(), e))?;
+        // One slot per worker bounds how many encoded files wait in memory
+        let (sender, receiver) = std::sync::mpsc::sync_channel::<ZipEntry>(rayon::current_num_threads());
+        let writer = std::thread::spawn(move || -> anyhow::Result<()> {
+            use std::io::Write;
+            let mut zip = zip::ZipWriter::new(std::io::BufWriter::new(file));
+            let entry_options = zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Stored);
+            for (name, bytes, done) in receiver {
+                let written = zip.start_file(name, entry_options)
+                    .map_err(std::io::Error::from)
+                    .and_then(|()| zip.write_all(&bytes));
+                // The worker only goes away if its thread died; nothing left to notify
+                let _ = done.send(written);
+            }
+            zip.finish()?.flush()?;
+            Ok(())
+        });
+        Ok(ZipSink { path, sender, writer })
+    }
+
+    /// Queues one file for the archive and waits until it has been written.
+    fn write(&self, name: String, bytes: Vec<u8>) -> anyhow::Result<()> {
+        let (done, result) = std::sync::mpsc::channel();
+        self.sender.send((name, bytes, done))
+            .map_err(|_| anyhow::anyhow!("zip writer stopped"))?;
+        result.recv().map_err(|_| anyhow::anyhow!("zip writer stopped"))??;
+        Ok(())
+    }
+
+    /// Closes the channel and writes the archive's central directory.
+    fn finish(self) -> anyhow::Result<()> {
+        drop(self.sender);
+        self.writer.join().map_err(|_| anyhow::anyhow!("zip writer panicked"))?
+    }
+}
+
+/// Where a batch writes its files: the `--output` directory or an `--output-zip` archive.
+enum OutputSink {
+    Dir(PathBuf),
+    Zip(ZipSink),
+}
+
+impl OutputSink {
+    /// Directory or archive path, for logging.
+    fn path(&self) -> &Path {
+        match self {
+            OutputSink::Dir(dir) => dir,
+            OutputSink::Zip(zip) => &zip.path,
+        }
+    }
+
+    /// Writes `bytes` as `name` (a `/`-separated relative path) and returns the absolute
+    /// path reported for it.
+    fn write(&self, name: &str, bytes: Vec<u8>) -> anyhow::Result<PathBuf> {
+        match self {
+            OutputSink::Dir(dir) => {
+                let path = dir.join(name);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&path, bytes)?;
+                Ok(std::path::absolute(&path)?)
+            },
+            OutputSink::Zip(zip) => {
+                zip.write(name.to_string(), bytes)?;
+                Ok(zip.path.join(name))
+            },
+        }
+    }
+
+    /// Finalizes the archive, if any.
+    fn finish(self) -> anyhow::Result<()> {
+        match self {
+            OutputSink::Dir(_) => Ok(()),
+            OutputSink::Zip(zip) => zip.finish(),
+        }
+    }
+}
+
+/// Confirms that the file at `path` holds exactly `expected` and decodes to `width` x `height`.
+fn verify_output(path: &Path, expected: &[u8], width: u32, height: u32) -> anyhow::Result<()> {
+    let written = std::fs::read(path)?;
+    if blake3::hash(&written) != blake3::hash(expected) {
+        anyhow::bail!("checksum mismatch: {} bytes on disk, {} encoded", written.len(), expected.len());
+    }
+    let decoded = image::load_from_memory(&written)?;
+    if (decoded.width(), decoded.height()) != (width, height) {
+        anyhow::bail!("decoded as {}x{}, expected {}x{}", decoded.width(), decoded.height(), width, height);
+    }
+    Ok(())
+}
+
+/// Downscales an image so its longest edge is at most `edge`, preserving aspect ratio.
+/// Images that already fit are copied unchanged (never upscaled).
+fn resize_to_fit(img: &DynamicImage, edge: u32, filter: ResizeFilter) -> DynamicImage {
+    if img.width().max(img.height()) > edge {
+        img.resize(edge, edge, filter.into())
+    } else {
+        img.clone()
+    }
+}
+
+/// Gap in pixels around contact-sheet cells.
+const SHEET_GAP: u32 = 8;
+
+/// Height in pixels of the caption strip under each contact-sheet thumbnail.
+const SHEET_CAPTION_HEIGHT: u32 = 12;
+
+/// Paginated proofing montage for `--contact-sheet`.
+///
+/// Worker threads fill the pages concurrently, each placing its thumbnail at the cell for
+/// the file's position in the batch. A page is allocated on its first thumbnail and
+/// written and dropped as soon as every file in its range is `done`, so only pages still
+/// in progress are held in memory; pages left incomplete are written by `finish`.
+struct ContactSheet {
+    columns: u32,
+    rows: u32,
+    cell: u32,
+    /// Files in the batch, which fixes the number of pages and the cells on the last one.
+    count: usize,
+    path: PathBuf,
+    pages: Vec<Mutex<SheetPage>>,
+    /// First page write that failed mid-batch, returned by `finish`.
+    error: Mutex<Option<anyhow::Error>>,
+}
+
+/// State of one contact-sheet page.
+#[derive(Default)]
+struct SheetPage {
+    /// `None` until the first thumbnail lands, and again once the page is written.
+    canvas: Option<image::RgbImage>,
+    /// Files in this page's range that have finished, placed or not.
+    done: usize,
+    written: bool,
+}
+
+impl ContactSheet {
+    /// Prepares pages for `count` thumbnails, to be written to `path` and its siblings.
+    fn new(columns: u32, rows: u32, cell: u32, count: usize, path: &Path) -> ContactSheet {
+        let per_page = (columns * rows) as usize;
+        let pages = (0..count.div_ceil(per_page)).map(|_| Mutex::new(SheetPage::default())).collect();
+        ContactSheet { columns, rows, cell, count, path: path.to_path_buf(), pages, error: Mutex::new(None) }
+    }
+
+    fn per_page(&self) -> usize {
+        (self.columns * self.rows) as usize
+    }
+
+    fn blank_page(&self) -> image::RgbImage {
+        let width = self.columns * (self.cell + SHEET_GAP) + SHEET_GAP;
+        let height = self.rows * (self.cell + SHEET_CAPTION_HEIGHT + SHEET_GAP) + SHEET_GAP;
+        image::RgbImage::from_pixel(width, height, Rgb([240, 240, 240]))
+    }
+
+    /// Page `index` (0-based) is written to the sheet path itself for the first page and
+    /// to `<stem>_<n>.<ext>` for the rest.
+    fn page_path(&self, index: usize) -> PathBuf {
+        if index == 0 {
+            return self.path.clone();
+        }
+        let stem = self.path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        let ext = self.path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+        self.path.with_file_name(format!("{}_{}{}", stem, index + 1, ext))
+    }
+
+    /// Draws a thumbnail of `img` and its caption into the cell for batch `position`.
+    fn place(&self, position: usize, img: &DynamicImage, filter: ResizeFilter, caption: &str) {
+        let per_page = self.per_page();
+        let Some(page) = self.pages.get(position / per_page) else {
+            return;
+        };
+        let slot = (position % per_page) as u32;
+        let x0 = SHEET_GAP + (slot % self.columns) * (self.cell + SHEET_GAP);
+        let y0 = SHEET_GAP + (slot / self.columns) * (self.cell + SHEET_CAPTION_HEIGHT + SHEET_GAP);
+
+        let tile = resize_to_fit(img, self.cell, filter).to_rgb8();
+        let (dx, dy) = ((self.cell - tile.width()) / 2, (self.cell - tile.height()) / 2);
+        let mut page = page.lock().unwrap();
+        if page.written {
+            return;
+        }
+        let canvas = page.canvas.get_or_insert_with(|| self.blank_page());
+        image::imageops::replace(canvas, &tile, (x0 + dx) as i64, (y0 + dy) as i64);
+        draw_caption(canvas, x0, y0 + self.cell + 3, self.cell, caption);
+    }
+
+    /// Marks the file at batch `position` as finished, whether or not it was placed, and
+    /// writes its page once that was the page's last outstanding file.
+    fn done(&self, position: usize) {
+        let per_page = self.per_page();
+        let index = position / per_page;
+        let Some(page) = self.pages.get(index) else {
+            return;
+        };
+        let mut page = page.lock().unwrap();
+        page.done += 1;
+        if page.done == per_page.min(self.count - index * per_page) {
+            if let Err(e) = self.write_page(index, &mut page) {
+                tracing::warn!(page = %self.page_path(index).display(), error = %e, "failed to write contact sheet page");
+                self.error.lock().unwrap().get_or_insert(e);
+            }
+        }
+    }
+
+    fn write_page(&self, index: usize, page: &mut SheetPage) -> anyhow::Result<()> {
+        page.written = true;
+        let canvas = page.canvas.take().unwrap_or_else(|| self.blank_page());
+        let path = self.page_path(index);
+        canvas.save(&path).map_err(|e| anyhow::anyhow!("failed to write contact sheet {}: {}", path.display(), e))
+    }
+
+    /// Writes the pages still incomplete (after failures or an abort) and returns the
+    /// first page write that failed, if any.
+    fn finish(self) -> anyhow::Result<()> {
+        for (index, page) in self.pages.iter().enumerate() {
+            let mut page = page.lock().unwrap();
+            if !page.written {
+                self.write_page(index, &mut page)?;
+            }
+        }
+        match self.error.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Renders `text` in the built-in 5x7 font (uppercased, one pixel between glyphs),
+/// truncated to `max_width` pixels.
+fn draw_caption(canvas: &mut image::RgbImage, x: u32, y: u32, max_width: u32, text: &str) {
+    let max_chars = (max_width / 6) as usize;
+    for (i, ch) in text.chars().take(max_chars).enumerate() {
+        let gx = x + i as u32 * 6;
+        for (row, bits) in glyph(ch.to_ascii_uppercase()).iter().enumerate() {
+            for col in 0..5 {
+                if bits & (0x10 >> col) != 0 {
+                    canvas.put_pixel(gx + col, y + row as u32, Rgb([40, 40, 40]));
+                }
+            }
+        }
+    }
+}
+
+/// 5x7 bitmap for caption characters; each row's low five bits run left to right.
+/// Characters outside the set render as `?`.
+fn glyph(ch: char) -> [u8; 7] {
+    match ch {
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+/// Content heuristic behind `OutputFormat::Auto`; samples every fourth row.
+fn looks_like_graphics(img: &DynamicImage) -> bool {
+    let rgb = img.to_rgb8();
+    let mut colors = std::collections::HashSet::new();
+    let mut flat = 0usize;
+    let mut sampled = 0usize;
+    for y in (0..rgb.height()).step_by(4) {
+        let mut prev = None;
+        for x in 0..rgb.width() {
+            let px = rgb.get_pixel(x, y).0;
+            if colors.len() <= AUTO_PNG_MAX_COLORS {
+                colors.insert(px);
+            }
+            if prev == Some(px) {
+                flat += 1;
+            }
+            prev = Some(px);
+            sampled += 1;
+        }
+    }
+    colors.len() <= AUTO_PNG_MAX_COLORS || (sampled > 0 && flat as f32 / sampled as f32 > AUTO_PNG_FLAT_RATIO)
+}
+
+/// Returns the file name used to label a path in progress messages and output names.
+fn display_name(path: &Path) -> String {
+    path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Whether a decode failure stems from I/O that may succeed on another attempt, as
+/// opposed to a malformed, truncated or unsupported file.
+fn is_transient(e: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+    e.chain().any(|cause| {
+        let io = cause.downcast_ref::<std::io::Error>().or_else(|| match cause.downcast_ref::<image::ImageError>() {
+            Some(image::ImageError::IoError(io)) => Some(io),
+            _ => None,
+        });
+        io.is_some_and(|io| matches!(io.kind(),
+            ErrorKind::Interrupted
+                | ErrorKind::TimedOut
+                | ErrorKind::WouldBlock
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::BrokenPipe))
+    })
+}
+
+/// Checks that a path names a regular file the process is allowed to open.
+fn is_readable_file(path: &Path) -> bool {
+    path.is_file() && std::fs::File::open(path).is_ok()
+}
+
+/// Runs one file through the pipeline for `--preview` and writes a before/after image.
+///
+/// The original and processed images are placed side by side on a transparent canvas
+/// tall enough for both (filters such as `border` change the size). Returns the
+/// absolute path of the written PNG.
+fn write_preview(path: &Path, options: &ProcessOptions, output_dir: &Path, mmap: bool) -> anyhow::Result<String> {
+    let name = display_name(path);
+    let name_lower = name.to_lowercase();
+    if name_lower.ends_with(".cr3") {
+        anyhow::bail!("CR3 not supported");
+    }
+    let original = if is_raw_name(&name_lower) {
+        decode_raw(&path.to_string_lossy(), options, mmap)?
+    } else {
+        decode_standard(path, mmap)?
+    };
+    let lut = options.lut.as_deref().map(CubeLut::load).transpose()?;
+    let mask = options.mask.as_deref().map(load_mask).transpose()?;
+    let processed = apply_filters(original.clone(), options, lut.as_ref(), mask.as_ref(), None);
+
+    let (before, after) = (original.to_rgba8(), processed.to_rgba8());
+    let mut canvas = image::RgbaImage::new(before.width() + after.width(), before.height().max(after.height()));
+    image::imageops::replace(&mut canvas, &before, 0, 0);
+    image::imageops::replace(&mut canvas, &after, before.width() as i64, 0);
+
+    std::fs::create_dir_all(output_dir)?;
+    let out_path = output_dir.join(format!("preview_{}.png", name));
+    canvas.save(&out_path)?;
+    Ok(std::path::absolute(&out_path)?.to_string_lossy().to_string())
+}
+
+/// Emits a batch-level failure (startup, or finalizing the output archive or contact
+/// sheet) as an `error` progress message not tied to any one file.
+fn report_startup_error(e: &anyhow::Error) {
+    Progress::new(0.0, "", format!("error: {}", e), 0, 0).emit();
+}
+
+/// Passes `result` through for `?`, reporting an error with `report_startup_error` first.
+fn reported<T>(result: anyhow::Result<T>) -> anyhow::Result<T> {
+    result.inspect_err(report_startup_error)
+}
+
+/// Core Orchestrator for ClioBulk-X.
+///
+/// Responsible for:
+/// 1. Bootstrapping the CLI environment and parsing parameters.
+/// 2. Discovering input assets (from string lists or JSON manifests).
+/// 3. Spawning a high-concurrency Rayon pool for image processing.
+/// 4. Managing file-system operations and IPC reporting.
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    if let Some(log_path) = &args.log_file {
+        let file = reported(std::fs::OpenOptions::new().create(true).append(true).open(log_path)
+            .map_err(|e| anyhow::anyhow!("failed to open log file {}: {}", log_path, e)))?;
+        tracing_subscriber::fmt()
+            .with_writer(Mutex::new(file))
+            .with_ansi(false)
+            .with_thread_ids(true)
+            .with_max_level(args.log_level)
+            .init();
+    }
+
+    // Schema introspection mode: describe the expected options payload and exit
+    if args.print_schema {
+        println!("{}", serde_json::to_string_pretty(&schemars::schema_for!(ProcessOptions))?);
+        return Ok(());
+    }
+
+    // Merge config defaults with explicit options, then reject out-of-range parameters
+    // before any file is touched
+    let options = reported(load_options(args.config.as_deref(), args.options.as_deref()))?;
+
+    if let Some(preview) = &args.preview {
+        // Clap requires `--output` here: `--output-zip` conflicts with `--preview`
+        let Some(output) = &args.output else {
+            unreachable!("required arguments enforced by clap");
+        };
+        let name = display_name(Path::new(preview));
+        return match write_preview(Path::new(preview), &options, Path::new(&output), args.mmap) {
+            Ok(out) => {
+                Progress::new(100.0, name, "saved", 1, 1).with_output(out).emit();
+                Ok(())
+            },
+            Err(e) => {
+                Progress::new(0.0, name, format!("error: {}", e), 1, 1).emit();
+                Err(e)
+            }
+        };
+    }
+
+    // Clap guarantees inputs unless `--print-schema` or `--preview` was given
+    let Some(inputs) = args.inputs else {
+        unreachable!("required arguments enforced by clap");
+    };
+
+    let mut input_paths = reported(resolve_inputs(&inputs, &options))?;
+    if !args.only_ext.is_empty() || !args.skip_ext.is_empty() {
+        let normalize = |list: &[String]| -> Vec<String> {
+            list.iter().map(|e| e.trim().trim_start_matches('.').to_lowercase()).collect()
+        };
+        let (only, skip) = (normalize(&args.only_ext), normalize(&args.skip_ext));
+        input_paths.retain(|job| {
+            let ext = Path::new(&job.path).extension()
+                .map(|e| e.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            (only.is_empty() || only.contains(&ext)) && !skip.contains(&ext)
+        });
+    }
+    if args.deterministic {
+        // Stable, so manifest entries repeating a path keep their relative order
+        input_paths.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+    if args.sort_by_capture_time {
+        let mut keyed: Vec<(CaptureTime, InputJob)> = input_paths
+            .into_par_iter()
+            .map(|job| (CaptureTime::of(Path::new(&job.path)), job))
+            .collect();
+        keyed.sort_by(|a, b| a.0.cmp(&b.0));
+        input_paths = keyed.into_iter()
+            .enumerate()
+            .map(|(i, (_, job))| InputJob { sequence: Some(i + 1), ..job })
+            .collect();
+    }
+    if let Some(limit) = args.limit {
+        input_paths.truncate(limit);
+    }
+
+    let input_paths = reported(require_inputs(input_paths))?;
+
+    // Parse every referenced LUT and mask once up front; jobs share them through `Arc`.
+    let mut file_hashes = std::collections::HashMap::new();
+    let luts = reported(load_shared(&options, &input_paths, |o| o.lut.as_ref(), CubeLut::load, &mut file_hashes))?;
+    let masks = reported(load_shared(&options, &input_paths, |o| o.mask.as_ref(), load_mask, &mut file_hashes))?;
+
+    // The report on disk is brought up to date after every entry; a failed write only
+    // warns here, and the final save after the batch reports it properly
+    let report = Mutex::new(BatchReport::default());
+    let record = |entry: ReportEntry| {
+        let mut report = report.lock().unwrap();
+        report.record(entry, args.deterministic);
+        if let Some(report_path) = &args.report {
+            if let Err(e) = report.save(report_path) {
+                tracing::warn!(report = %report_path, error = %e, "failed to update report");
+            }
+        }
+    };
+
+    // Resume: drop inputs a previous report records as done. This trusts the report
+    // rather than probing the output directory, which is much faster on network storage.
+    let (input_paths, skipped): (Vec<InputJob>, Vec<(InputJob, String)>) = match &args.resume {
+        Some(resume_path) => {
+            let previous = reported(BatchReport::load(resume_path))?;
+            let completed = previous.completed();
+            let mut pending = Vec::new();
+            let mut skipped = Vec::new();
+            for job in input_paths {
+                match completed.get(job.path.as_str()) {
+                    Some(out) => {
+                        let out = out.to_string();
+                        skipped.push((job, out));
+                    },
+                    None => pending.push(job),
+                }
+            }
+            (pending, skipped)
+        },
+        None => (input_paths, Vec::new()),
+    };
+
+    // Pre-flight check: report typo'd or unreadable paths immediately and drop them
+    // from the batch so the percentage math only covers files that can be processed.
+    let (input_paths, missing): (Vec<InputJob>, Vec<InputJob>) = input_paths
+        .into_iter()
+        .partition(|job| is_readable_file(Path::new(&job.path)));
+
+    // Content cache: hash every readable input and drop those matching a cached result
+    let cache = match &args.cache {
+        Some(cache_path) => Some(Mutex::new(reported(HashCache::load(cache_path))?)),
+        None => None,
+    };
+    let mut hashes: std::collections::HashMap<String, String> = std::collections::HashMap::new();
+    let mut cached: Vec<(InputJob, String)> = Vec::new();
+    let input_paths: Vec<InputJob> = match &cache {
+        Some(cache) => {
+            let hashed: Vec<(InputJob, Option<String>)> = input_paths
+                .into_par_iter()
+                .map(|job| {
+                    let hash = content_hash(Path::new(&job.path), job.options.as_ref().unwrap_or(&options), &file_hashes).ok();
+                    (job, hash)
+                })
+                .collect();
+            let cache = cache.lock().unwrap();
+            let mut pending = Vec::new();
+            for (job, hash) in hashed {
+                match (cache.entries.get(&job.path), hash) {
+                    (Some(entry), Some(hash)) if entry.hash == hash => {
+                        let out = entry.output_path.clone();
+                        cached.push((job, out));
+                    },
+                    (_, hash) => {
+                        if let Some(hash) = hash {
+                            hashes.insert(job.path.clone(), hash);
+                        }
+                        pending.push(job);
+                    },
+                }
+            }
+            pending
+        },
+        None => input_paths,
+    };
+    let total = input_paths.len();
+    for (job, out) in &cached {
+        Progress::new(0.0, display_name(Path::new(&job.path)), "cached", 0, total).with_output(out).emit();
+        record(ReportEntry {
+            input: job.path.clone(),
+            status: "cached".to_string(),
+            output_path: Some(out.clone()),
+            error: None,
+        });
+    }
+    for (job, out) in &skipped {
+        Progress::new(0.0, display_name(Path::new(&job.path)), "skipped", 0, total).with_output(out).emit();
+        record(ReportEntry {
+            input: job.path.clone(),
+            status: "skipped".to_string(),
+            output_path: Some(out.clone()),
+            error: None,
+        });
+    }
+    for job in &missing {
+        Progress::new(0.0, display_name(Path::new(&job.path)), "missing", 0, total).emit();
+        record(ReportEntry {
+            input: job.path.clone(),
+            status: "missing".to_string(),
+            output_path: None,
+            error: None,
+        });
+    }
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let failures = Arc::new(AtomicUsize::new(missing.len()));
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let abort_file: Mutex<Option<String>> = Mutex::new(None);
+    let bench = BenchStats::default();
+
+    // Ensure output target exists
+    let sink = match (&args.output_zip, &args.output) {
+        (Some(zip_path), _) => ZipSink::create(Path::new(zip_path)).map(OutputSink::Zip),
+        (None, Some(output)) => {
+            let output_dir = PathBuf::from(output);
+            if output_dir.exists() {
+                Ok(OutputSink::Dir(output_dir))
+            } else {
+                std::fs::create_dir_all(&output_dir)
+                    .map_err(|e| anyhow::anyhow!("failed to create output directory {}: {}", output_dir.display(), e))
+                    .map(|()| OutputSink::Dir(output_dir))
+            }
+        },
+        (None, None) => unreachable!("required arguments enforced by clap"),
+    };
+    let sink = reported(sink)?;
+
+    // Phase 1 (--normalize-batch): measure a shared reference on an even sample of inputs
+    let reference: Option<Arc<BatchReference>> = if args.normalize_batch && !input_paths.is_empty() {
+        let step = input_paths.len().div_ceil(NORMALIZE_SAMPLE_FILES);
+        let means: Vec<[f64; 3]> = input_paths
+            .par_iter()
+            .step_by(step)
+            .filter_map(|job| {
+                let path = Path::new(&job.path);
+                let name = display_name(path);
+                if !args.quiet {
+                    Progress::new(0.0, &name, "analyzing", 0, total).emit();
+                }
+                let job_options = job.options.as_ref().unwrap_or(&options);
+                let decoded = if is_raw_name(&name.to_lowercase()) {
+                    decode_raw(&job.path, job_options, args.mmap)
+                } else {
+                    decode_standard(path, args.mmap)
+                };
+                // Unreadable files are left for the main pass to report
+                decoded.ok().map(|img| channel_means(&img))
+            })
+            .collect();
+        let reference = BatchReference::from_means(&means);
+        tracing::info!(sampled = means.len(), multipliers = ?reference.map(|r| r.multipliers), "batch reference computed");
+        reference.map(Arc::new)
+    } else {
+        None
+    };
+
+    // Phase 2 / Parallel Processing Loop: Rayon automatically scales across all available CPU cores.
+    tracing::info!(threads = rayon::current_num_threads(), files = total, missing = missing.len(), skipped = skipped.len(), output = %sink.path().display(), "batch started");
+    let started = std::time::Instant::now();
+    let contact_sheet = args.contact_sheet.as_ref()
+        .map(|sheet_path| ContactSheet::new(args.sheet_columns, args.sheet_rows, args.sheet_cell, total, Path::new(sheet_path)));
+    input_paths.into_par_iter().enumerate().for_each(|(position, job)| {
+        if cancelled.load(Ordering::SeqCst) {
+            return;
+        }
+        let path_str = job.path;
+        let sequence = job.sequence.map(|n| format!("{:04}_", n)).unwrap_or_default();
+        let options = job.options.as_ref().unwrap_or(&options);
+        let lut = options.lut.as_ref().map(|p| Arc::clone(&luts[p]));
+        let mask = options.mask.as_ref().map(|p| Arc::clone(&masks[p]));
+        let reference = reference.clone();
+        let path = Path::new(&path_str);
+        let name = display_name(path);
+        
+        let c = counter.fetch_add(1, Ordering::SeqCst);
+        // Reproducible runs number files by their sorted position, not by completion
+        let index = if args.deterministic { position + 1 } else { c + 1 };
+        // Where this file starts; per-page and retry updates are measured from here
+        let file_progress = (c as f32 / total as f32) * 100.0;
+        // Print JSON progress update for the parent GUI process
+        if !args.quiet {
+            Progress::new(file_progress, &name, "processing", index, total).emit();
+        }
+
+        // `Ok(None)` means the file was deliberately not saved (`--min-sharpness`)
+        let res = (|| -> anyhow::Result<Option<String>> {
+            let name_lower = name.to_lowercase();
+            // Select appropriate decoder based on file extension
+            // Canon CR3 is an ISO-BMFF container that rawloader cannot parse; say so plainly
+            // instead of surfacing a cryptic decoder failure that looks like file corruption.
+            if name_lower.ends_with(".cr3") {
+                anyhow::bail!("CR3 not supported");
+            }
+            let is_raw = is_raw_name(&name_lower);
+            let decode = || if is_raw {
+                decode_raw(&path_str, options, args.mmap)
+            } else {
+                decode_standard(path, args.mmap)
+            };
+
+            // RAW decodes take seconds; let the GUI show why this file is still busy
+            if is_raw && !args.quiet {
+                Progress::new(file_progress, &name, "decoding_raw", index, total).emit();
+            }
+
+            // Flaky network mounts: retry reads that failed for I/O reasons, not bad data
+            let mut attempt = 0;
+            tracing::debug!(file = %path_str, raw = is_raw, "decode started");
+            let decode_start = std::time::Instant::now();
+            let img = loop {
+                match decode() {
+                    Ok(img) => break img,
+                    Err(e) if attempt < args.retries && is_transient(&e) => {
+                        attempt += 1;
+                        tracing::warn!(file = %path_str, attempt, error = %e, "transient decode error, retrying");
+                        Progress::new(file_progress, &name, "retrying", index, total).emit();
+                        std::thread::sleep(retry_delay(attempt));
+                    },
+                    Err(e) => return Err(e),
+                }
+            };
+            let decode_time = BenchStats::add(&bench.decode_ns, decode_start);
+            tracing::debug!(file = %path_str, width = img.width(), height = img.height(), ms = decode_time.as_millis() as u64, "decode finished");
+
+            // Multi-page TIFFs and animated GIFs: the decode above produced the first page
+            // Page 1 is already decoded, so trouble reading further pages never fails the file
+            let mut pages = if is_raw {
+                None
+            } else {
+                PageReader::open(path, args.mmap).unwrap_or_else(|e| {
+                    tracing::warn!(file = %path_str, error = %e, "cannot read further pages, processing the first only");
+                    None
+                })
+            };
+            let page_count = pages.as_ref().map_or(1, |pages| pages.count);
+            if page_count > 1 {
+                tracing::debug!(file = %path_str, pages = page_count, "multi-page input");
+            }
+
+            // Runs one page through filters and output; `Ok(None)` if it was rejected.
+            // Progress advances by a fraction of a file per page so it tracks real work.
+            // Each page gets its own "saved" message, and multi-page inputs name their
+            // outputs `processed_<name>_page<N>.<ext>`.
+            let process_page = |mut img: DynamicImage, page: usize, decode_time: std::time::Duration| -> anyhow::Result<Option<String>> {
+                let progress = ((c as f32 + page as f32 / page_count as f32) / total as f32) * 100.0;
+                let page_suffix = if page_count > 1 { format!("_page{}", page) } else { String::new() };
+                let pixels = img.width() as u64 * img.height() as u64;
+
+                if let Some(min) = args.min_sharpness {
+                    let score = sharpness(&img);
+                    if score < min {
+                        tracing::info!(file = %path_str, page, sharpness = score, min, "rejected as blurry");
+                        Progress::new(progress, &name, "rejected_blurry", index, total).emit();
+                        return Ok(None);
+                    }
+                    tracing::debug!(file = %path_str, page, sharpness = score, "focus check passed");
+                }
+
+                let filter_start = std::time::Instant::now();
+                img = apply_filters(img, options, lut.as_deref(), mask.as_deref(), reference.as_deref());
+                let filter_time = BenchStats::add(&bench.filter_ns, filter_start);
+
+                let save_start = std::time::Instant::now();
+                // Encoder follows the requested format; JPEG falls back to PNG to preserve alpha
+                let format = options.output_format.resolve(&img);
+                let ext = format.extension();
+                let out_name = format!("processed_{}{}{}.{}", sequence, name, page_suffix, ext);
+                let encoded = encode_output(&img, format, options.max_output_bytes, options.jpeg_chroma_subsampling)?;
+                let out_path = if args.verify_output {
+                    let mut attempt = 0;
+                    loop {
+                        let out_path = sink.write(&out_name, encoded.clone())?;
+                        match verify_output(&out_path, &encoded, img.width(), img.height()) {
+                            Ok(()) => break out_path,
+                            Err(e) if attempt < args.retries => {
+                                attempt += 1;
+                                tracing::warn!(file = %path_str, output = %out_path.display(), attempt, error = %e, "output verification failed, rewriting");
+                                Progress::new(file_progress, &name, "retrying", index, total).emit();
+                            },
+                            Err(e) => return Err(e.context("output verification failed")),
+                        }
+                    }
+                } else {
+                    sink.write(&out_name, encoded)?
+                };
+
+                // Tell the GUI where the result landed so it can show it immediately
+                let abs_out = out_path.to_string_lossy().to_string();
+                let save_time = BenchStats::add(&bench.save_ns, save_start);
+                tracing::info!(
+                    file = %path_str,
+                    page,
+                    output = %abs_out,
+                    decode_ms = decode_time.as_millis() as u64,
+                    filter_ms = filter_time.as_millis() as u64,
+                    save_ms = save_time.as_millis() as u64,
+                    "saved"
+                );
+                if !args.quiet {
+                    Progress::new(progress, &name, "saved", index, total).with_output(&abs_out).emit();
+                }
+
+                if args.write_sidecar {
+                    let sidecar = Sidecar {
+                        core_version: env!("CARGO_PKG_VERSION"),
+                        source: &path_str,
+                        output: &abs_out,
+                        options,
+                    };
+                    let sidecar_name = Path::new(&out_name).with_extension("json").to_string_lossy().to_string();
+                    let written = serde_json::to_string_pretty(&sidecar)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|json| sink.write(&sidecar_name, json.into_bytes()));
+                    if let Err(e) = written {
+                        tracing::warn!(file = %path_str, sidecar = %sidecar_name, error = %e, "failed to write sidecar");
+                    }
+                }
+
+                // One contact-sheet cell per input, showing its first page
+                if let (Some(sheet), 1) = (&contact_sheet, page) {
+                    sheet.place(position, &img, options.resize_filter, &name);
+                }
+
+                // Optional thumbnail sidecar from the same decoded image (no second read/decode)
+                if let Some(edge) = options.thumbnail {
+                    let thumb_start = std::time::Instant::now();
+                    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_else(|| name.clone());
+                    let thumb = resize_to_fit(&img, edge, options.resize_filter);
+                    sink.write(&format!("thumbs/thumb_{}{}{}.{}", sequence, stem, page_suffix, ext), encode_output(&thumb, format, None, options.jpeg_chroma_subsampling)?)?;
+                    BenchStats::add(&bench.save_ns, thumb_start);
+                }
+                bench.pixels.fetch_add(pixels, Ordering::Relaxed);
+                Ok(Some(abs_out))
+            };
+
+            // The file's result is its first saved page. Pages after the first that cannot
+            // be decoded are logged and skipped, like sidecars, rather than failing the file.
+            let mut output = process_page(img, 1, decode_time)?;
+            if let Some(pages) = &mut pages {
+                for page in 2..=page_count {
+                    let decode_start = std::time::Instant::now();
+                    let img = match pages.next_page() {
+                        Ok(img) => img,
+                        Err(e) => {
+                            tracing::warn!(file = %path_str, page, pages = page_count, error = %e, "page could not be decoded, skipping it");
+                            continue;
+                        },
+                    };
+                    let decode_time = BenchStats::add(&bench.decode_ns, decode_start);
+                    output = output.or(process_page(img, page, decode_time)?);
+                }
+            }
+            let Some(abs_out) = output else {
+                return Ok(None);
+            };
+
+            if let (Some(cache), Some(cache_path), Some(hash)) = (&cache, &args.cache, hashes.get(&path_str)) {
+                let mut cache = cache.lock().unwrap();
+                cache.entries.insert(path_str.clone(), CacheEntry { hash: hash.clone(), output_path: abs_out.clone() });
+                // A cache write failure only costs a re-run of this file later; keep the output
+                if let Err(e) = cache.save(cache_path) {
+                    tracing::warn!(cache = %cache_path, error = %e, "failed to update cache");
+                }
+            }
+            bench.files.fetch_add(1, Ordering::Relaxed);
+            Ok(Some(abs_out))
+        })();
+
+        let entry = match &res {
+            Ok(Some(out)) => ReportEntry { input: path_str.clone(), status: "saved".to_string(), output_path: Some(out.clone()), error: None },
+            Ok(None) => ReportEntry { input: path_str.clone(), status: "rejected_blurry".to_string(), output_path: None, error: None },
+            Err(e) => ReportEntry { input: path_str.clone(), status: "error".to_string(), output_path: None, error: Some(e.to_string()) },
+        };
+        record(entry);
+        // Its cell is final now, drawn or empty, which may complete a contact-sheet page
+        if let Some(sheet) = &contact_sheet {
+            sheet.done(position);
+        }
+
+        // Error handling during the batch loop: report error but continue with the remaining items.
+        if let Err(e) = res {
+            // `{:?}` on anyhow errors includes the cause chain and any captured backtrace
+            tracing::error!(file = %path_str, "processing failed: {:?}", e);
+            failures.fetch_add(1, Ordering::SeqCst);
+            if args.abort_on_error && !cancelled.swap(true, Ordering::SeqCst) {
+                *abort_file.lock().unwrap() = Some(name.clone());
+            }
+            Progress::new(file_progress, name, format!("error: {}", e), index, total).emit();
+        }
+    });
+
+    // Entries reached disk as they came in; a last save surfaces a write that kept failing
+    if let Some(report_path) = &args.report {
+        let report = report.into_inner().unwrap();
+        reported(report.save(report_path).map_err(|e| anyhow::anyhow!("failed to write report {}: {}", report_path, e)))?;
+    }
+
+    // The archive is unreadable without its central directory, so this failure is fatal
+    reported(sink.finish().map_err(|e| anyhow::anyhow!("failed to finalize output archive: {}", e)))?;
+
+    if let Some(sheet) = contact_sheet {
+        reported(sheet.finish())?;
+    }
+
+    // Signal completion (or the abort and its cause) to the parent process
+    let wall = started.elapsed();
+    tracing::info!(failed = failures.load(Ordering::SeqCst), wall_ms = wall.as_millis() as u64, aborted = abort_file.lock().unwrap().is_some(), "batch finished");
+    let abort_file = abort_file.into_inner().unwrap();
+    match &abort_file {
+        Some(file) => Progress::new((counter.load(Ordering::SeqCst) as f32 / total as f32) * 100.0, file, "aborted", counter.load(Ordering::SeqCst), total).emit(),
+        None => Progress::new(100.0, "Done", "complete", total, total).emit(),
+    }
+
+    if args.bench {
+        println!("{}", serde_json::to_string(&bench.summary(wall)).unwrap());
+    }
+
+
+
+    if let Some(file) = abort_file {
+        anyhow::bail!("batch aborted after failure on {}", file);
+    }
+
+    // Exit contract for scripted/CI callers: the progress stream above is unaffected
+    let failed = failures.load(Ordering::SeqCst);
+    if args.fail_on_error > 0 && failed >= args.fail_on_error {
+        anyhow::bail!("{} of {} files failed", failed, total + missing.len() + skipped.len() + cached.len());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a CMYK JPEG whose samples are stored as-is (0 = no ink), i.e. without the
+    /// Adobe APP14 segment and its inverted-ink convention, as scanner software writes them.
+    fn plain_cmyk_jpeg(width: u16, height: u16, ink: impl Fn(u16, u16) -> [u8; 4]) -> Vec<u8> {
+        // `jpeg_encoder` stores `255 - v` and tags the file Adobe; pre-invert, then drop the tag
+        let ink = &ink;
+        let data: Vec<u8> = (0..height)
+            .flat_map(|y| (0..width).flat_map(move |x| ink(x, y)))
+            .map(|v| 255 - v)
+            .collect();
+        let mut buf = Vec::new();
+        let mut encoder = jpeg_encoder::Encoder::new(&mut buf, 100);
+        encoder.set_sampling_factor(jpeg_encoder::SamplingFactor::F_1_1);
+        encoder.encode(&data, width, height, jpeg_encoder::ColorType::Cmyk).unwrap();
+
+        let app14 = buf.windows(2).position(|w| w == [0xFF, 0xEE]).expect("APP14 segment");
+        let len = u16::from_be_bytes([buf[app14 + 2], buf[app14 + 3]]) as usize;
+        buf.drain(app14..app14 + 2 + len);
+        buf
+    }
+
+    #[test]
+    fn plain_cmyk_jpeg_paper_white_decodes_to_white() {
+        // Left 8x8 block: no ink at all; right block: full cyan only
+        let bytes = plain_cmyk_jpeg(16, 8, |x, _| if x < 8 { [0, 0, 0, 0] } else { [255, 0, 0, 0] });
+        let layout = sniff_jpeg(&bytes).expect("JPEG header");
+        assert_eq!((layout.components, layout.adobe), (4, false));
+
+        let rgb = decode_plain_cmyk_jpeg(&bytes).unwrap().to_rgb8();
+        let close = |actual: &Rgb<u8>, expected: [u8; 3]| {
+            actual.0.iter().zip(expected).all(|(&a, e)| a.abs_diff(e) <= 3)
+        };
+        assert!(close(rgb.get_pixel(3, 3), [255, 255, 255]), "paper white came out {:?}", rgb.get_pixel(3, 3));
+        assert!(close(rgb.get_pixel(12, 3), [0, 255, 255]), "cyan came out {:?}", rgb.get_pixel(12, 3));
+    }
+
+    #[test]
+    fn gif_frames_are_counted_without_decoding() {
+        for count in [1usize, 3] {
+            let mut buf = Vec::new();
+            {
+                let mut encoder = image::codecs::gif::GifEncoder::new(&mut buf);
+                for i in 0..count {
+                    let frame = image::RgbaImage::from_pixel(4, 4, image::Rgba([i as u8 * 80, 0, 0, 255]));
+                    encoder.encode_frame(image::Frame::new(frame)).unwrap();
+                }
+            }
+            assert_eq!(count_gif_frames(&mut std::io::Cursor::new(&buf)).unwrap(), count);
+        }
+        assert!(count_gif_frames(&mut std::io::Cursor::new(b"GIF89a")).is_err());
+    }
+
+    #[test]
+    fn io_failures_are_transient_but_bad_data_is_not() {
+        let timed_out = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        assert!(is_transient(&timed_out.context("reading RAW")));
+        let missing = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert!(!is_transient(&missing));
+        assert!(!is_transient(&anyhow::anyhow!("Couldn't find a decoder")));
+    }
+
+    /// Neutral options (every adjustment off) with `overrides` merged on top.
+    fn options(overrides: serde_json::Value) -> ProcessOptions {
+        let mut value = serde_json::json!({
+            "brightness": 0.0,
+            "contrast": 1.0,
+            "saturation": 1.0,
+            "adaptive_threshold": false,
+            "denoise": false,
+        });
+        value.as_object_mut().unwrap().extend(overrides.as_object().unwrap().clone());
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn batch_reference_only_changes_exposure_of_grayscale_images() {
+        // A warm, dark batch: the color reference boosts blue and the whole exposure
+        let reference = BatchReference::from_means(&[[90.0, 60.0, 30.0], [70.0, 50.0, 40.0]]).unwrap();
+        assert!(reference.multipliers[2] > reference.multipliers[0]);
+
+        let gray = DynamicImage::ImageLuma8(image::GrayImage::from_fn(8, 8, |x, y| image::Luma([(x * 8 + y * 4) as u8])));
+        let out = apply_filters(gray.clone(), &options(serde_json::json!({})), None, None, Some(&reference)).to_rgb8();
+        for (pixel, source) in out.pixels().zip(gray.to_luma8().pixels()) {
+            assert_eq!(pixel[0], pixel[1]);
+            assert_eq!(pixel[1], pixel[2]);
+            assert_eq!(pixel[0], (source[0] as f32 * reference.exposure).clamp(0.0, 255.0) as u8);
+        }
+    }
+
+    #[test]
+    fn float_and_integer_raw_cells_agree() {
+        // The same 8x4 RGGB scene as 16-bit counts and as normalized floats, with two
+        // different greens per cell so the averaging is exercised
+        let (width, height) = (8, 4);
+        let scene: Vec<u16> = (0..width * height).map(|i| (i as u32 * 65535 / 31) as u16).collect();
+        let floats: Vec<f32> = scene.iter().map(|&v| v as f32 / 65535.0).collect();
+        let offsets = [0, 1, width, width + 1];
+
+        let int = demosaic_half(&scene, width, height, offsets, bayer_cell_int).unwrap().to_rgb8();
+        let float = demosaic_half(&floats, width, height, offsets, bayer_cell_float).unwrap().to_rgb8();
+        assert_eq!(int.dimensions(), (4, 2));
+        for (a, b) in int.pixels().zip(float.pixels()) {
+            for c in 0..3 {
+                assert!(a[c].abs_diff(b[c]) <= 1, "{a:?} vs {b:?}");
+            }
+        }
+
+        // Saturated greens must not overshoot in the float branch
+        assert_eq!(bayer_cell_float(&[0.0, 1.0, 1.0, 0.0], 0, [0, 1, 2, 3]), [0, 255, 0]);
+        assert_eq!(bayer_cell_float(&[0.0, 1.4, 1.2, 0.0], 0, [0, 1, 2, 3]), [0, 255, 0]);
+        assert_eq!(bayer_cell_int(&[0, 65535, 65535, 0], 0, [0, 1, 2, 3]), [0, 255, 0]);
+    }
+
+    #[test]
+    fn bayer_offsets_follow_the_sensor_layout() {
+        let width = 100;
+        let offsets = |pattern: &str| bayer_offsets(&rawloader::CFA::new(pattern), width);
+        assert_eq!(offsets("RGGB"), Some([0, 1, width, width + 1]));
+        assert_eq!(offsets("BGGR"), Some([width + 1, 1, width, 0]));
+        assert_eq!(offsets("GRBG"), Some([1, 0, width + 1, width]));
+        assert_eq!(offsets("GBRG"), Some([width, 0, width + 1, 1]));
+
+        // Greens in one column, a fourth color, and X-Trans are not Bayer cells
+        assert_eq!(offsets("RGBG"), None);
+        assert_eq!(offsets("RGBE"), None);
+        assert_eq!(offsets("GGRGGBGGBGGRBRGRBGGGBGGRGGRGGBRBGBRG"), None);
+    }
+
+    #[test]
+    fn brightness_keeps_alpha_with_and_without_denoise() {
+        // Left half fully transparent, right half opaque, over a color gradient
+        let rgba = image::RgbaImage::from_fn(16, 8, |x, y| image::Rgba([(x * 15) as u8, (y * 30) as u8, 90, if x < 8 { 0 } else { 255 }]));
+        for denoise in [false, true] {
+            let opts = options(serde_json::json!({ "brightness": 0.2, "denoise": denoise }));
+            let out = apply_filters(DynamicImage::ImageRgba8(rgba.clone()), &opts, None, None, None);
+            let DynamicImage::ImageRgba8(out) = out else { panic!("alpha channel dropped") };
+            for (x, y, pixel) in out.enumerate_pixels() {
+                assert_eq!(pixel[3], rgba.get_pixel(x, y)[3], "alpha changed at ({x}, {y}), denoise={denoise}");
+            }
+            assert!(out.get_pixel(12, 4)[2] > 90, "brightness was not applied");
+        }
+    }
+
+    #[test]
+    fn posterize_lut_yields_exactly_n_values() {
+        for levels in 2..=32u8 {
+            let lut = posterize_lut(levels);
+            let distinct: std::collections::BTreeSet<u8> = lut.iter().copied().collect();
+            assert_eq!(distinct.len(), levels as usize, "levels={levels}");
+            assert_eq!((lut[0], lut[255]), (0, 255));
+        }
+    }
+
+    #[test]
+    fn inverting_twice_restores_the_image() {
+        let rgb = image::RgbImage::from_fn(16, 16, |x, y| image::Rgb([(x * 16) as u8, (y * 16) as u8, (x * y) as u8]));
+        let opts = options(serde_json::json!({ "invert": true }));
+        let once = apply_filters(DynamicImage::ImageRgb8(rgb.clone()), &opts, None, None, None);
+        assert_eq!(once.to_rgb8().get_pixel(1, 2).0, [239, 223, 253]);
+        let twice = apply_filters(once, &opts, None, None, None);
+        assert_eq!(twice.to_rgb8(), rgb);
+    }
+
+    #[test]
+    fn levels_lut_stretches_the_input_range() {
+        let levels = Levels { in_black: 50, in_white: 200, out_black: 0, out_white: 255, gamma: 1.0 };
+        let lut = levels.lut();
+        assert_eq!((lut[0], lut[50], lut[125], lut[200], lut[255]), (0, 0, 128, 255, 255));
+
+        // Gamma 2 lifts the midpoint to 255 * sqrt(0.5)
+        let lut = Levels { gamma: 2.0, ..levels }.lut();
+        assert_eq!((lut[50], lut[125], lut[200]), (0, 180, 255));
+
+        // A compressed output range maps the ends onto out_black and out_white
+        let lut = Levels { out_black: 20, out_white: 220, ..levels }.lut();
+        assert_eq!((lut[0], lut[125], lut[255]), (20, 120, 220));
+    }
+
+    #[test]
+    fn add_border_frames_rgb_rgba_and_luma() {
+        let border = Border { width_px: 3, color: [200, 100, 50] };
+        let cases = [
+            (DynamicImage::ImageRgb8(image::RgbImage::from_pixel(10, 6, Rgb([1, 2, 3]))), [200, 100, 50, 255], [1, 2, 3, 255]),
+            (DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(10, 6, image::Rgba([1, 2, 3, 0]))), [200, 100, 50, 255], [1, 2, 3, 0]),
+            // Luma outputs get the border color's luma (0.299 R + 0.587 G + 0.114 B = 124)
+            (DynamicImage::ImageLuma8(image::GrayImage::from_pixel(10, 6, image::Luma([7]))), [124, 124, 124, 255], [7, 7, 7, 255]),
+        ];
+        for (img, frame, inside) in cases {
+            let color = img.color();
+            let out = add_border(img, &border);
+            assert_eq!(out.color(), color);
+            assert_eq!((out.width(), out.height()), (16, 12));
+            let rgba = out.to_rgba8();
+            for (x, y) in [(0, 0), (15, 0), (0, 11), (15, 11), (2, 2), (13, 9)] {
+                assert_eq!(rgba.get_pixel(x, y).0, frame, "{color:?} frame at ({x}, {y})");
+            }
+            for (x, y) in [(3, 3), (12, 3), (3, 8), (12, 8)] {
+                assert_eq!(rgba.get_pixel(x, y).0, inside, "{color:?} image at ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn empty_lists_and_manifests_fail_with_no_inputs() {
+        let opts = options(serde_json::json!({}));
+        let check = |inputs: &str| resolve_inputs(inputs, &opts).and_then(require_inputs);
+        for inputs in [",", "", " , ,"] {
+            assert_eq!(check(inputs).unwrap_err().to_string(), "no inputs", "inputs {inputs:?}");
+        }
+        let paths: Vec<String> = check("a.jpg, ,b.png,").unwrap().into_iter().map(|job| job.path).collect();
+        assert_eq!(paths, ["a.jpg", "b.png"]);
+
+        let manifest = std::env::temp_dir().join(format!("cliobulk-empty-manifest-{}.json", std::process::id()));
+        std::fs::write(&manifest, "[]").unwrap();
+        let jobs = check(manifest.to_str().unwrap());
+        std::fs::remove_file(&manifest).unwrap();
+        assert_eq!(jobs.unwrap_err().to_string(), "no inputs");
+    }
+
+    #[test]
+    fn raw_demosaic_option_selects_the_path() {
+        let (width, height) = (12, 12);
+        let scene: Vec<u16> = (0..width * height).map(|i| (i * 397 % 65536) as u16).collect();
+        let run = |pattern: &str, overrides: serde_json::Value| {
+            let cfa = rawloader::CFA::new(pattern);
+            let opts = options(overrides);
+            let img = demosaic(&scene, width, height, &cfa, &opts, |i| scene[i] as f32 / 256.0, bayer_cell_int).unwrap();
+            (img.width(), img.height())
+        };
+
+        let bayer = "RGGB";
+        assert_eq!(run(bayer, serde_json::json!({})), (6, 6));
+        assert_eq!(run(bayer, serde_json::json!({ "raw_demosaic": "fast", "dither": true })), (6, 6));
+        assert_eq!(run(bayer, serde_json::json!({ "raw_demosaic": "bilinear" })), (12, 12));
+        assert_eq!(run(bayer, serde_json::json!({ "raw_demosaic": "vng" })), (12, 12));
+
+        // X-Trans always takes the 3x3 cell average, whatever the requested quality
+        let xtrans = "GGRGGBGGBGGRBRGRBGGGBGGRGGRGGBRBGBRG";
+        assert_eq!(run(xtrans, serde_json::json!({})), (4, 4));
+        assert_eq!(run(xtrans, serde_json::json!({ "raw_demosaic": "vng" })), (4, 4));
+    }
+
+    #[test]
+    fn cube_lut_parses_resolve_keywords() {
+        let text = "\
+TITLE \"identity\"
+# Resolve writes both ranges, even for a 3D-only file
+LUT_1D_INPUT_RANGE 0.0 1.0
+LUT_3D_INPUT_RANGE 0.0 1.0
+LUT_3D_SIZE 2
+LUT_IN_VIDEO_RANGE
+
+0.0 0.0 0.0
+1.0 0.0 0.0
+0.0 1.0 0.0
+1.0 1.0 0.0
+0.0 0.0 1.0
+1.0 0.0 1.0
+0.0 1.0 1.0
+1.0 1.0 1.0
+";
+        let lut = CubeLut::parse(text).unwrap();
+        assert_eq!((lut.size, lut.domain_min, lut.domain_max), (2, [0.0; 3], [1.0; 3]));
+        for rgb in [[0, 0, 0], [10, 128, 250], [255, 255, 255]] {
+            assert_eq!(lut.apply(rgb), rgb);
+        }
+
+        // A half range stretches 0.0 - 0.5 over the whole table
+        let lut = CubeLut::parse(&text.replace("LUT_3D_INPUT_RANGE 0.0 1.0", "LUT_3D_INPUT_RANGE 0.0 0.5")).unwrap();
+        assert_eq!((lut.domain_min, lut.domain_max), ([0.0; 3], [0.5; 3]));
+        assert_eq!(lut.apply([64, 128, 255]), [128, 255, 255]);
+    }
+
+    #[test]
+    fn deskew_only_straightens_documents() {
+        // Dark text lines on paper, tilted by about 4 degrees
+        let tilt = 4f32.to_radians().tan();
+        let page = image::GrayImage::from_fn(400, 400, |x, y| {
+            let row = (y as f32 - x as f32 * tilt).rem_euclid(24.0);
+            image::Luma([if row < 5.0 { 25 } else { 235 }])
+        });
+        assert!(looks_like_document(&page));
+        let skew = detect_skew(&DynamicImage::ImageLuma8(page), false).expect("document skew");
+        assert!((skew.abs() - 4.0).abs() <= 1.0, "skew {skew}");
+
+        // A photo: shaded sky over shaded ground with a similarly tilted horizon
+        let photo = image::RgbImage::from_fn(400, 400, |x, y| {
+            let shade = ((x + y) * 255 / 800) as u8;
+            if (y as f32) < 200.0 + x as f32 * tilt { Rgb([shade / 2 + 80, shade / 2 + 100, 255 - shade / 4]) } else { Rgb([shade, shade, shade / 2]) }
+        });
+        let photo = DynamicImage::ImageRgb8(photo);
+        assert!(!looks_like_document(&photo.to_luma8()));
+        assert_eq!(detect_skew(&photo, false), None);
+        // Even with bright sky and dark ground, their smooth shading is not ink and paper
+        let contrasty = image::GrayImage::from_fn(400, 400, |x, y| {
+            let shade = (x * 100 / 400) as u8;
+            image::Luma([if (y as f32) < 200.0 + x as f32 * tilt { 150 + shade } else { shade }])
+        });
+        assert!(!looks_like_document(&contrasty));
+        // adaptive_threshold marks the input as a document regardless of its histogram
+        assert!(detect_skew(&photo, true).is_some());
+    }
+
+    #[test]
+    fn batch_report_is_saved_entry_by_entry() {
+        let entry = |input: &str, status: &str| ReportEntry { input: input.to_string(), status: status.to_string(), output_path: Some(format!("/out/{input}")), error: None };
+        let path = std::env::temp_dir().join(format!("cliobulk-report-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut report = BatchReport::default();
+        for (input, status) in [("c.png", "saved"), ("a.png", "error"), ("b.png", "saved"), ("a.png", "saved")] {
+            report.record(entry(input, status), true);
+            report.save(path).unwrap();
+            // What is on disk after each file is everything recorded so far
+            assert_eq!(BatchReport::load(path).unwrap().entries.len(), report.entries.len());
+        }
+        let loaded = BatchReport::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let order: Vec<(&str, &str)> = loaded.entries.iter().map(|e| (e.input.as_str(), e.status.as_str())).collect();
+        assert_eq!(order, [("a.png", "error"), ("a.png", "saved"), ("b.png", "saved"), ("c.png", "saved")]);
+        assert_eq!(loaded.completed().len(), 3);
+    }
+
+    #[test]
+    fn retry_backoff_doubles_up_to_the_cap() {
+        let delays: Vec<u64> = (1..=8).map(|attempt| retry_delay(attempt).as_millis() as u64).collect();
+        assert_eq!(delays, [200, 400, 800, 1600, 3200, 6400, 10_000, 10_000]);
+        // Attempt counts far past the point where `200 << n` would overflow
+        assert_eq!(retry_delay(65).as_millis(), 10_000);
+        assert_eq!(retry_delay(u32::MAX).as_millis(), 10_000);
+    }
+
+    #[test]
+    fn contact_sheet_pages_are_written_as_they_fill() {
+        let dir = std::env::temp_dir().join(format!("cliobulk-sheet-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sheet_path = dir.join("sheet.png");
+        // Two cells per page, five files: pages of 2, 2 and 1
+        let sheet = ContactSheet::new(2, 1, 16, 5, &sheet_path);
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, Rgb([200, 0, 0])));
+        let page = |n: usize| sheet.page_path(n);
+
+        sheet.place(0, &img, ResizeFilter::default(), "a");
+        sheet.done(0);
+        assert!(sheet.pages[0].lock().unwrap().canvas.is_some());
+        assert!(!page(0).exists());
+        // The second file fails without a thumbnail; its page is complete all the same
+        sheet.done(1);
+        assert!(page(0).exists());
+        assert!(sheet.pages[0].lock().unwrap().canvas.is_none(), "written page still held in memory");
+
+        sheet.place(4, &img, ResizeFilter::default(), "e");
+        sheet.done(4);
+        assert!(page(2).exists());
+
+        // Page 2 only got one of its two files (an abort, say); `finish` writes it anyway
+        sheet.place(2, &img, ResizeFilter::default(), "c");
+        sheet.done(2);
+        assert!(!page(1).exists());
+        let second = page(1);
+        sheet.finish().unwrap();
+        assert_eq!(second, dir.join("sheet_2.png"));
+        assert_eq!(image::open(&second).unwrap().to_rgb8().get_pixel(SHEET_GAP + 8, SHEET_GAP + 8).0, [200, 0, 0]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn content_hash_follows_lut_file_contents() {
+        let dir = std::env::temp_dir().join(format!("cliobulk-hash-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("in.png");
+        std::fs::write(&input, b"not really a png").unwrap();
+        let lut = dir.join("look.cube");
+        let identity = "LUT_3D_SIZE 2\n0 0 0\n1 0 0\n0 1 0\n1 1 0\n0 0 1\n1 0 1\n0 1 1\n1 1 1\n";
+        let opts = options(serde_json::json!({ "lut": lut }));
+        let jobs = [InputJob { path: input.to_string_lossy().to_string(), options: None, sequence: None }];
+        let hash_with_lut = |text: &str| {
+            std::fs::write(&lut, text).unwrap();
+            let mut file_hashes = std::collections::HashMap::new();
+            load_shared(&opts, &jobs, |o| o.lut.as_ref(), CubeLut::load, &mut file_hashes).unwrap();
+            content_hash(&input, &opts, &file_hashes).unwrap()
+        };
+
+        let before = hash_with_lut(identity);
+        assert_eq!(hash_with_lut(identity), before);
+        // Same path, same options JSON, edited table
+        assert_ne!(hash_with_lut(&identity.replace("1 1 1\n", "1 1 0.5\n")), before);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -313,14 +313,14 @@ class ClioBulkX(QMainWindow):
         """Imports external image assets into the local session queue."""
         paths, _ = QFileDialog.getOpenFileNames(
             self, "Import Assets", "", 
-            "Images (*.png *.jpg *.jpeg *.webp *.arw *.cr2 *.nef *.dng)"
+            "Images (*.png *.jpg *.jpeg *.webp *.arw *.cr2 *.nef *.dng *.pef *.raf *.rw2 *.orf)"
         )
         for p in paths:
             if p not in self.files:
                 self.files.append(p)
                 item = QListWidgetItem(os.path.basename(p))
                 # For standard formats, generate a thumbnail icon
-                if not any(p.lower().endswith(ext) for ext in ['.arw', '.cr2', '.nef', '.dng']):
+                if not any(p.lower().endswith(ext) for ext in ['.arw', '.cr2', '.nef', '.dng', '.pef', '.raf', '.rw2', '.orf']):
                     item.setIcon(QIcon(p))
                 self.file_list.addItem(item)
 
@@ -339,7 +339,7 @@ class ClioBulkX(QMainWindow):
         previews are generated dynamically by the native core during processing.
         """
         path = self.files[self.file_list.row(item)]
-        if any(path.lower().endswith(ext) for ext in ['.arw', '.cr2', '.nef', '.dng']):
+        if any(path.lower().endswith(ext) for ext in ['.arw', '.cr2', '.nef', '.dng', '.pef', '.raf', '.rw2', '.orf']):
             self.preview_lbl.setPixmap(QPixmap()) 
             self.preview_lbl.setText(f"RAW ASSET: {os.path.basename(path)}\n(Optimized Native Processing Active)")
         else: